@@ -0,0 +1,530 @@
+use crate::chip8::PROGRAM_START;
+use crate::instruction::Instruction;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// deep enough for any sane project, shallow enough to catch a file including itself
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// A problem in the source, with where it was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblerError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+impl From<AssemblerError> for String {
+    fn from(error: AssemblerError) -> Self {
+        error.to_string()
+    }
+}
+
+/// Assembles Cowgod syntax, the same as `chip8-disasm` prints, into a ROM
+/// that starts at 0x200. Includes are looked up relative to the working directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblerError> {
+    let mut lines = Vec::new();
+    read_lines("<source>", source, Path::new("."), 0, &mut lines)?;
+    Assembler::new(lines).assemble()
+}
+
+/// Like `assemble`, with includes looked up relative to the file.
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Vec<u8>, AssemblerError> {
+    let mut lines = Vec::new();
+    include(path.as_ref(), 0, &mut lines, "<command line>", 0)?;
+    Assembler::new(lines).assemble()
+}
+
+struct SourceLine {
+    file: String,
+    number: usize,
+    text: String,
+}
+
+impl SourceLine {
+    fn error(&self, message: impl Into<String>) -> AssemblerError {
+        AssemblerError {
+            file: self.file.clone(),
+            line: self.number,
+            message: message.into(),
+        }
+    }
+}
+
+fn include(
+    path: &Path,
+    depth: usize,
+    lines: &mut Vec<SourceLine>,
+    from_file: &str,
+    from_line: usize,
+) -> Result<(), AssemblerError> {
+    let error = |message: String| AssemblerError {
+        file: from_file.to_string(),
+        line: from_line,
+        message,
+    };
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(error(String::from("includes are nested too deeply")));
+    }
+
+    let source = fs::read_to_string(path)
+        .map_err(|e| error(format!("could not read {}: {}", path.display(), e)))?;
+    let directory = path.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+    read_lines(
+        &path.display().to_string(),
+        &source,
+        &directory,
+        depth,
+        lines,
+    )
+}
+
+// flattens includes into one list of lines, each remembering where it came from
+fn read_lines(
+    file: &str,
+    source: &str,
+    directory: &Path,
+    depth: usize,
+    lines: &mut Vec<SourceLine>,
+) -> Result<(), AssemblerError> {
+    for (index, text) in source.lines().enumerate() {
+        let text = strip_comment(text).trim();
+        let (first, rest) = split_word(text);
+        if first.eq_ignore_ascii_case("include") {
+            let name = rest.trim().trim_matches('"');
+            include(&directory.join(name), depth + 1, lines, file, index + 1)?;
+            continue;
+        }
+
+        lines.push(SourceLine {
+            file: file.to_string(),
+            number: index + 1,
+            text: text.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+fn strip_comment(text: &str) -> &str {
+    text.split(';').next().unwrap_or_default()
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+// operand keywords and register names can't double as labels or constants
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    let well_formed = chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+
+    well_formed
+        && matches!(Operand::parse(text), Operand::Value(_))
+        && !text.eq_ignore_ascii_case("long")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Register(u8),
+    RegisterRange(u8, u8),
+    I,
+    IndirectI,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    Font,
+    BigFont,
+    Bcd,
+    Flags,
+    Long(String),
+    Value(String),
+}
+
+impl Operand {
+    fn parse(text: &str) -> Operand {
+        let register = |text: &str| match text.as_bytes() {
+            [b'v' | b'V', digit] => (*digit as char).to_digit(16).map(|digit| digit as u8),
+            _ => None,
+        };
+
+        if let Some(x) = register(text) {
+            return Operand::Register(x);
+        }
+        if let Some((x, y)) = text.split_once('-') {
+            if let (Some(x), Some(y)) = (register(x.trim()), register(y.trim())) {
+                return Operand::RegisterRange(x, y);
+            }
+        }
+
+        let (first, rest) = split_word(text);
+        if first.eq_ignore_ascii_case("long") && !rest.is_empty() {
+            return Operand::Long(rest.to_string());
+        }
+
+        match text.to_ascii_uppercase().as_str() {
+            "I" => Operand::I,
+            "[I]" => Operand::IndirectI,
+            "DT" => Operand::DelayTimer,
+            "ST" => Operand::SoundTimer,
+            "K" => Operand::Key,
+            "F" => Operand::Font,
+            "HF" => Operand::BigFont,
+            "B" => Operand::Bcd,
+            "R" => Operand::Flags,
+            _ => Operand::Value(text.to_string()),
+        }
+    }
+}
+
+// one line that makes bytes, waiting for the second pass to resolve its operands
+struct Statement {
+    line: usize,
+    mnemonic: String,
+    operands: Vec<Operand>,
+}
+
+struct Assembler {
+    lines: Vec<SourceLine>,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, String>,
+    statements: Vec<Statement>,
+}
+
+impl Assembler {
+    fn new(lines: Vec<SourceLine>) -> Assembler {
+        Assembler {
+            lines,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            statements: Vec::new(),
+        }
+    }
+
+    fn assemble(mut self) -> Result<Vec<u8>, AssemblerError> {
+        self.collect_symbols()?;
+
+        let mut rom = Vec::new();
+        for statement in &self.statements {
+            let line = &self.lines[statement.line];
+            let bytes = self
+                .encode(statement)
+                .map_err(|message| line.error(message))?;
+            rom.extend(bytes);
+        }
+
+        Ok(rom)
+    }
+
+    // first pass: where every label lands and what every constant is
+    fn collect_symbols(&mut self) -> Result<(), AssemblerError> {
+        let mut addr = PROGRAM_START;
+
+        for (index, line) in self.lines.iter().enumerate() {
+            let mut text = line.text.as_str();
+            while let Some((label, rest)) = text.split_once(':') {
+                let label = label.trim();
+                if !is_identifier(label) {
+                    return Err(line.error(format!("'{}' can't be used as a label", label)));
+                }
+                if self.labels.insert(label.to_string(), addr as u16).is_some() {
+                    return Err(line.error(format!("label '{}' is defined twice", label)));
+                }
+                text = rest.trim();
+            }
+            if text.is_empty() {
+                continue;
+            }
+
+            let (mnemonic, rest) = split_word(text);
+            let (second, value) = split_word(rest);
+            if second.eq_ignore_ascii_case("equ") {
+                if !is_identifier(mnemonic) {
+                    return Err(
+                        line.error(format!("'{}' can't be used as a constant name", mnemonic))
+                    );
+                }
+                if self
+                    .constants
+                    .insert(mnemonic.to_string(), value.to_string())
+                    .is_some()
+                {
+                    return Err(line.error(format!("constant '{}' is defined twice", mnemonic)));
+                }
+                continue;
+            }
+
+            let mnemonic = mnemonic.to_ascii_uppercase();
+            let operands: Vec<Operand> = match rest.is_empty() {
+                true => Vec::new(),
+                false => rest
+                    .split(',')
+                    .map(|operand| Operand::parse(operand.trim()))
+                    .collect(),
+            };
+            addr += match (mnemonic.as_str(), operands.as_slice()) {
+                ("DB", _) => operands.len(),
+                ("DW", _) => operands.len() * 2,
+                ("LD", [Operand::I, Operand::Long(_)]) => 4,
+                _ => 2,
+            };
+            if addr > u16::MAX as usize + 1 {
+                return Err(line.error("program doesn't fit in 64KB of memory"));
+            }
+
+            self.statements.push(Statement {
+                line: index,
+                mnemonic,
+                operands,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn encode(&self, statement: &Statement) -> Result<Vec<u8>, String> {
+        use Operand::*;
+
+        let operands = statement.operands.as_slice();
+        let data = |width: u32| -> Result<Vec<u8>, String> {
+            let mut bytes = Vec::new();
+            for operand in operands {
+                let Value(text) = operand else {
+                    return Err(String::from("data has to be numbers or symbols"));
+                };
+                let value = self.number(text, (1 << (8 * width)) - 1)?;
+                bytes.extend(&value.to_be_bytes()[4 - width as usize..]);
+            }
+            Ok(bytes)
+        };
+        let addr = |text: &str| self.number(text, 0xFFF).map(|value| value as u16);
+        let byte = |text: &str| self.number(text, 0xFF).map(|value| value as u8);
+        let nibble = |text: &str| self.number(text, 0xF).map(|value| value as u8);
+
+        let instruction = match (statement.mnemonic.as_str(), operands) {
+            ("DB", _) => return data(1),
+            ("DW", _) => return data(2),
+            ("SYS", [Value(nnn)]) => Instruction::Sys(addr(nnn)?),
+            ("CLS", []) => Instruction::Cls,
+            ("RET", []) => Instruction::Ret,
+            ("SCD", [Value(n)]) => Instruction::ScrollDown(nibble(n)?),
+            ("SCU", [Value(n)]) => Instruction::ScrollUp(nibble(n)?),
+            ("SCR", []) => Instruction::ScrollRight,
+            ("SCL", []) => Instruction::ScrollLeft,
+            ("EXIT", []) => Instruction::Exit,
+            ("LOW", []) => Instruction::LowRes,
+            ("HIGH", []) => Instruction::HighRes,
+            ("JP", [Value(nnn)]) => Instruction::Jp(addr(nnn)?),
+            ("JP", [Register(0), Value(nnn)]) => Instruction::JpOffset(addr(nnn)?),
+            ("CALL", [Value(nnn)]) => Instruction::Call(addr(nnn)?),
+            ("SE", [Register(x), Register(y)]) => Instruction::SeReg { x: *x, y: *y },
+            ("SE", [Register(x), Value(nn)]) => Instruction::SeImm {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("SNE", [Register(x), Register(y)]) => Instruction::SneReg { x: *x, y: *y },
+            ("SNE", [Register(x), Value(nn)]) => Instruction::SneImm {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("LD", [Register(x), Register(y)]) => Instruction::LdReg { x: *x, y: *y },
+            ("LD", [Register(x), Value(nn)]) => Instruction::LdImm {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("LD", [I, Value(nnn)]) => Instruction::LdI(addr(nnn)?),
+            ("LD", [I, Long(nnnn)]) => Instruction::LdILong(self.number(nnnn, 0xFFFF)? as u16),
+            ("LD", [Register(x), DelayTimer]) => Instruction::LdVxDt(*x),
+            ("LD", [Register(x), Key]) => Instruction::LdKey(*x),
+            ("LD", [DelayTimer, Register(x)]) => Instruction::LdDtVx(*x),
+            ("LD", [SoundTimer, Register(x)]) => Instruction::LdStVx(*x),
+            ("LD", [Font, Register(x)]) => Instruction::LdFont(*x),
+            ("LD", [BigFont, Register(x)]) => Instruction::LdBigFont(*x),
+            ("LD", [Bcd, Register(x)]) => Instruction::Bcd(*x),
+            ("LD", [IndirectI, Register(x)]) => Instruction::StoreRegs(*x),
+            ("LD", [Register(x), IndirectI]) => Instruction::LoadRegs(*x),
+            ("LD", [IndirectI, RegisterRange(x, y)]) => Instruction::SaveRange { x: *x, y: *y },
+            ("LD", [RegisterRange(x, y), IndirectI]) => Instruction::LoadRange { x: *x, y: *y },
+            ("LD", [Flags, Register(x)]) => Instruction::StoreFlags(*x),
+            ("LD", [Register(x), Flags]) => Instruction::LoadFlags(*x),
+            ("ADD", [Register(x), Register(y)]) => Instruction::AddReg { x: *x, y: *y },
+            ("ADD", [Register(x), Value(nn)]) => Instruction::AddImm {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("ADD", [I, Register(x)]) => Instruction::AddI(*x),
+            ("OR", [Register(x), Register(y)]) => Instruction::Or { x: *x, y: *y },
+            ("AND", [Register(x), Register(y)]) => Instruction::And { x: *x, y: *y },
+            ("XOR", [Register(x), Register(y)]) => Instruction::Xor { x: *x, y: *y },
+            ("SUB", [Register(x), Register(y)]) => Instruction::Sub { x: *x, y: *y },
+            ("SUBN", [Register(x), Register(y)]) => Instruction::Subn { x: *x, y: *y },
+            // Cowgod writes the shifts with just Vx, Vy only matters under the shift quirk
+            ("SHR", [Register(x)]) => Instruction::Shr { x: *x, y: *x },
+            ("SHR", [Register(x), Register(y)]) => Instruction::Shr { x: *x, y: *y },
+            ("SHL", [Register(x)]) => Instruction::Shl { x: *x, y: *x },
+            ("SHL", [Register(x), Register(y)]) => Instruction::Shl { x: *x, y: *y },
+            ("RND", [Register(x), Value(nn)]) => Instruction::Rnd {
+                x: *x,
+                nn: byte(nn)?,
+            },
+            ("DRW", [Register(x), Register(y), Value(n)]) => Instruction::Drw {
+                x: *x,
+                y: *y,
+                n: nibble(n)?,
+            },
+            ("SKP", [Register(x)]) => Instruction::Skp(*x),
+            ("SKNP", [Register(x)]) => Instruction::Sknp(*x),
+            ("AUDIO", []) => Instruction::LdAudio,
+            ("PLANE", [Value(n)]) => Instruction::Plane(self.number(n, 0x3)? as u8),
+            ("PITCH", [Register(x)]) => Instruction::LdPitch(*x),
+            (mnemonic, _) => {
+                return Err(format!(
+                    "'{}' isn't a known instruction, or has the wrong operands",
+                    mnemonic
+                ))
+            }
+        };
+
+        Ok(instruction.encode())
+    }
+
+    // a sum of numbers, labels and constants, like `sprites + 0x10`
+    fn number(&self, text: &str, max: u32) -> Result<u32, String> {
+        let value = self.evaluate(text, 0)?;
+        if !(0..=max as i64).contains(&value) {
+            return Err(format!(
+                "{} ({}) is out of range, it has to fit in {:#x}",
+                text, value, max
+            ));
+        }
+
+        Ok(value as u32)
+    }
+
+    fn evaluate(&self, text: &str, depth: usize) -> Result<i64, String> {
+        if depth > self.constants.len() {
+            return Err(format!("'{}' is defined in terms of itself", text));
+        }
+
+        let mut total = 0;
+        let mut sign = 1;
+        let mut term = String::new();
+        for c in text.chars().chain(std::iter::once('+')) {
+            match c {
+                '+' | '-' => {
+                    let term_text = term.trim();
+                    if term_text.is_empty() {
+                        return Err(format!("'{}' is not a valid expression", text));
+                    }
+                    total += sign * self.term(term_text, depth)?;
+                    sign = if c == '-' { -1 } else { 1 };
+                    term.clear();
+                }
+                _ => term.push(c),
+            }
+        }
+
+        Ok(total)
+    }
+
+    fn term(&self, text: &str, depth: usize) -> Result<i64, String> {
+        let parsed = if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+            i64::from_str_radix(hex, 16).ok()
+        } else if let Some(binary) = text.strip_prefix("0b").or(text.strip_prefix("0B")) {
+            i64::from_str_radix(binary, 2).ok()
+        } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+            text.parse().ok()
+        } else if let Some(&addr) = self.labels.get(text) {
+            Some(addr as i64)
+        } else if let Some(value) = self.constants.get(text) {
+            return self.evaluate(value, depth + 1);
+        } else {
+            return Err(format!("'{}' is not defined", text));
+        };
+
+        parsed.ok_or_else(|| format!("'{}' is not a valid number", text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::disassembler::{Disassembly, Syntax};
+
+    #[test]
+    fn assemble_test() {
+        let source = "
+            SPEED EQU 3          ; constants can be used anywhere a number can
+            main:
+                LD V0, SPEED
+                LD I, sprite + 1
+                CALL draw
+            loop: JP loop
+            draw:
+                DRW V0, V1, 4
+                SHR V2
+                LD V1-V3, [I]
+                RET
+            sprite:
+                DB 0xFF, 0b10010000, 144
+                DW 0x1234
+        ";
+        let rom = assemble(source).unwrap();
+
+        assert_eq!(
+            vec![
+                0x60, 0x03, 0xA2, 0x11, 0x22, 0x08, 0x12, 0x06, 0xD0, 0x14, 0x82, 0x26, 0x51, 0x33,
+                0x00, 0xEE, 0xFF, 0x90, 0x90, 0x12, 0x34
+            ],
+            rom
+        );
+    }
+
+    #[test]
+    fn disassembly_round_trip_test() {
+        // a long I load, a subroutine, sprite data and two bytes nothing reaches
+        let rom = [
+            0x22, 0x08, 0xF0, 0x00, 0x02, 0x10, 0x12, 0x06, 0xA2, 0x10, 0xD0, 0x01, 0x00, 0xEE,
+            0x12, 0x34, 0xF0, 0x90, 0x90, 0xF0,
+        ];
+        let listing = Disassembly::new(&rom).listing(Syntax::Cowgod);
+
+        assert_eq!(rom.to_vec(), assemble(&listing).unwrap());
+
+        // 0NNN machine code calls in old VIP ROMs come back too
+        let rom = [0x02, 0xA4, 0x12, 0x02];
+        let listing = Disassembly::new(&rom).listing(Syntax::Cowgod);
+        assert_eq!(rom.to_vec(), assemble(&listing).unwrap());
+    }
+
+    #[test]
+    fn errors_point_at_the_line_test() {
+        let error = assemble("CLS\nLD V0, 256\n").unwrap_err();
+        assert_eq!(2, error.line);
+
+        let error = assemble("JP nowhere").unwrap_err();
+        assert_eq!("<source>:1: 'nowhere' is not defined", error.to_string());
+
+        assert!(assemble("a:\na:\n").is_err());
+        assert!(assemble("f: RET").is_err());
+        assert!(assemble("LD K, V0").is_err());
+    }
+}
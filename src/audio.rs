@@ -1,54 +1,138 @@
 use rodio::{source::Source, OutputStream, Sink};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SAMPLE_RATE: u32 = 44100;
+const BEEP_FREQUENCY: f32 = 440.0;
+const AMPLITUDE: f32 = 0.8;
+const PATTERN_BITS: f32 = 128.0;
+// samples between looking at the shared voice, 10ms so the lock is rarely contended
+const VOICE_BLOCK: u32 = SAMPLE_RATE / 100;
+
+// what the sound timer should sound like right now, shared with the rodio thread
+#[derive(Clone, Copy)]
+struct Voice {
+    playing: bool,
+    pattern: Option<[u8; 16]>,
+    pattern_rate: f32,
+}
 
 pub struct AudioDriver {
     // found out i needed to keep this stream alive the hard way.
     // hours lost: 3
-    stream: OutputStream,
-    sink: Sink,
-    samples: Vec<f32>,
-    sample_rate: u32,
+    _stream: OutputStream,
+    _sink: Sink,
+    voice: Arc<Mutex<Voice>>,
 }
 
 impl AudioDriver {
-    pub fn build() -> AudioDriver {
-        let (stream, stream_handle) = OutputStream::try_default().unwrap();
-        let sink = Sink::try_new(&stream_handle).unwrap();
-
-        // had to look this one up!
-        // this makes a beep
-        let freq = 440.0;
-        let sample_rate = 44100;
-        let duration_secs = 1;
-        let samples = Self::generate_square_wave(freq, duration_secs, sample_rate);
-
-        AudioDriver {
-            stream,
-            sink,
-            samples,
-            sample_rate,
-        }
+    pub fn build() -> Result<AudioDriver, String> {
+        let (stream, stream_handle) = OutputStream::try_default().map_err(|e| e.to_string())?;
+        let sink = Sink::try_new(&stream_handle).map_err(|e| e.to_string())?;
+
+        // a single endless source that goes quiet instead of being stopped and
+        // re-queued, so pattern changes and restarts don't leave gaps
+        let voice = Arc::new(Mutex::new(Voice {
+            playing: false,
+            pattern: None,
+            pattern_rate: 4000.0,
+        }));
+        sink.append(VoiceSource {
+            shared: Arc::clone(&voice),
+            voice: *voice.lock().unwrap(),
+            block_left: 0,
+            phase: 0.0,
+        });
+
+        Ok(AudioDriver {
+            _stream: stream,
+            _sink: sink,
+            voice,
+        })
     }
 
     pub fn play(&mut self) {
-        let source = rodio::buffer::SamplesBuffer::new(1, self.sample_rate, self.samples.clone());
-        self.sink.append(source.convert_samples::<f32>());
+        self.voice.lock().unwrap().playing = true;
     }
 
     pub fn stop(&mut self) {
-        self.sink.stop();
-    }
-
-    // square wave goes brrrrrr
-    fn generate_square_wave(freq: f32, duration_secs: u64, sample_rate: u32) -> Vec<f32> {
-        (0..sample_rate as u64 * duration_secs)
-            .map(move |x| {
-                let t = x as f32 / sample_rate as f32;
-                if (t * freq * 2.0 * std::f32::consts::PI).sin() >= 0.0 {
-                    0.8 // High part of the wave
-                } else {
-                    -0.8 // Low part of the wave
-                }
-            })
-            .collect()
+        self.voice.lock().unwrap().playing = false;
+    }
+
+    // the XO-CHIP sample and its playback rate in bits per second, None keeps the plain beep
+    pub fn set_pattern(&mut self, pattern: Option<[u8; 16]>, pattern_rate: f32) {
+        let mut voice = self.voice.lock().unwrap();
+        voice.pattern = pattern;
+        voice.pattern_rate = pattern_rate;
+    }
+}
+
+struct VoiceSource {
+    shared: Arc<Mutex<Voice>>,
+    // a copy of `shared` taken every VOICE_BLOCK samples
+    voice: Voice,
+    block_left: u32,
+    // in wave cycles for the beep, in pattern bits for XO-CHIP samples
+    phase: f32,
+}
+
+impl VoiceSource {
+    fn refresh(&mut self) {
+        let voice = *self.shared.lock().unwrap();
+        // the phase means something else in the other mode, start it over
+        if voice.pattern.is_some() != self.voice.pattern.is_some() {
+            self.phase = 0.0;
+        }
+        self.voice = voice;
+        self.block_left = VOICE_BLOCK;
+    }
+}
+
+impl Iterator for VoiceSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.block_left == 0 {
+            self.refresh();
+        }
+        self.block_left -= 1;
+
+        let voice = self.voice;
+        if !voice.playing {
+            return Some(0.0);
+        }
+
+        let high = match voice.pattern {
+            Some(pattern) => {
+                self.phase = (self.phase + voice.pattern_rate / SAMPLE_RATE as f32) % PATTERN_BITS;
+                let bit = self.phase as usize;
+                (pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1
+            }
+            None => {
+                // square wave goes brrrrrr
+                self.phase = (self.phase + BEEP_FREQUENCY / SAMPLE_RATE as f32) % 1.0;
+                self.phase < 0.5
+            }
+        };
+
+        Some(if high { AMPLITUDE } else { -AMPLITUDE })
+    }
+}
+
+impl Source for VoiceSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
     }
 }
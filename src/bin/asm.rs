@@ -0,0 +1,45 @@
+use chip8_emulator::{assembler, octo};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const USAGE: &str = "usage: chip8-asm <source> [-o <rom>]";
+
+pub fn main() -> Result<(), String> {
+    let mut source_path = None;
+    let mut rom_path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => rom_path = Some(args.next().ok_or(USAGE)?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if source_path.is_none() => source_path = Some(arg),
+            _ => return Err(String::from(USAGE)),
+        }
+    }
+
+    let source_path = source_path.ok_or(USAGE)?;
+    // game.asm (or game.8o) assembles to game.ch8 unless told otherwise
+    let rom_path = rom_path.unwrap_or_else(|| {
+        Path::new(&source_path)
+            .with_extension("ch8")
+            .display()
+            .to_string()
+    });
+
+    let rom = if source_path.ends_with(".8o") {
+        let source = fs::read_to_string(&source_path)
+            .map_err(|e| format!("could not read {}: {}", source_path, e))?;
+        octo::compile(&source).map_err(|e| format!("{}: {}", source_path, e))?
+    } else {
+        assembler::assemble_file(&source_path)?
+    };
+    fs::write(&rom_path, &rom).map_err(|e| format!("could not write {}: {}", rom_path, e))?;
+    println!("Wrote {} bytes to {}", rom.len(), rom_path);
+
+    Ok(())
+}
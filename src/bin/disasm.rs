@@ -0,0 +1,35 @@
+use chip8_emulator::disassembler::{Disassembly, Syntax};
+use std::env;
+use std::fs;
+
+const USAGE: &str = "usage: chip8-disasm [--syntax cowgod|octo] <rom>";
+
+pub fn main() -> Result<(), String> {
+    let mut syntax = Syntax::Cowgod;
+    let mut rom_path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--syntax" => {
+                syntax = match args.next().as_deref() {
+                    Some("cowgod") => Syntax::Cowgod,
+                    Some("octo") => Syntax::Octo,
+                    _ => return Err(String::from(USAGE)),
+                }
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => return Err(String::from(USAGE)),
+        }
+    }
+
+    let rom_path = rom_path.ok_or(USAGE)?;
+    let rom = fs::read(&rom_path).map_err(|e| format!("could not read {}: {}", rom_path, e))?;
+    print!("{}", Disassembly::new(&rom).listing(syntax));
+
+    Ok(())
+}
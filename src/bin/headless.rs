@@ -0,0 +1,33 @@
+use chip8_emulator::cli::{self, Command};
+use std::env;
+
+// the same as `chip8-emulator --headless`, but builds without SDL2 for CI machines
+const USAGE: &str = "usage: chip8-headless <frames> [chip8-emulator options] <rom>";
+
+pub fn main() -> Result<(), String> {
+    let mut args = env::args().skip(1);
+    let frames = match args.next().as_deref() {
+        Some("-h" | "--help") => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Some(frames) => frames.parse::<u64>().map_err(|_| USAGE)?,
+        None => return Err(String::from(USAGE)),
+    };
+
+    let options = match Command::parse(args)? {
+        Command::Help => {
+            println!("{}\n\n{}", USAGE, cli::HELP);
+            return Ok(());
+        }
+        Command::Run(options) => options,
+    };
+    if options.record.is_some() {
+        return Err(String::from(
+            "headless runs can't be recorded, they have no input",
+        ));
+    }
+
+    print!("{}", cli::run_headless(&options, frames)?);
+    Ok(())
+}
@@ -1,43 +1,94 @@
 extern crate rand;
-use crate::keyboard::Keyboard;
-use crate::pixel::Pixel;
-use rand::prelude::Rng;
-use sdl2::EventPump;
+use crate::error::Chip8Error;
+use crate::framebuffer::Framebuffer;
+use crate::instruction::Instruction;
+use crate::keypad::Keypad;
+use crate::quirks::{IndexIncrement, Quirks};
+use crate::random::{RandomSource, SplitMix64};
+use crate::save_state::{StateReader, StateWriter};
 use std::fs;
 
-const DEFAULT_CHIP8_PIXEL_HEIGHT: u32 = 32;
-const DEFAULT_CHIP8_PIXEL_WIDTH: u32 = 64;
+pub const DEFAULT_CHIP8_PIXEL_HEIGHT: u32 = 32;
+pub const DEFAULT_CHIP8_PIXEL_WIDTH: u32 = 64;
+const HIRES_CHIP8_PIXEL_HEIGHT: u32 = 64;
+const HIRES_CHIP8_PIXEL_WIDTH: u32 = 128;
+const BIG_FONT_START: usize = 0x50;
+pub const PROGRAM_START: usize = 0x200;
+// XO-CHIP extends the address space to 64KB, CHIP-8 programs never notice the extra room
+const MEMORY_SIZE: usize = 0x10000;
+const PLANE_COUNT: usize = 2;
+const STACK_SIZE: usize = 16;
+
+/// A data read or write made by the last instruction, instruction fetches aren't included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryAccess {
+    Read(std::ops::Range<usize>),
+    Write(std::ops::Range<usize>),
+}
 
 pub struct Chip8 {
-    memory: [u8; 4096],
+    memory: Vec<u8>,
     registers: [u8; 16],
     index_register: u16,
     stack: Vec<u16>,
     program_counter: u16,
     delay_timer: u8,
     sound_timer: u8,
-    pub vram: Vec<Vec<Pixel>>,
+    // one grid per XO-CHIP bitplane, plain CHIP-8 only ever draws to plane 0
+    pub vram: [Framebuffer; PLANE_COUNT],
     pub vram_changed: bool,
-    pub vram_scale: usize,
+    // the screen was last cleared or had pixels turned off by a sprite, so a redraw is
+    // probably half done. Only used to pick frames to show, never saved
+    erased: bool,
     awaiting_keypress: bool,
     awaiting_keylift: bool,
+    halt_key: u8,
+    awaiting_vblank: bool,
+    hires: bool,
+    halted: bool,
+    rpl_flags: [u8; 16],
+    selected_planes: u8,
+    audio_pattern: Option<[u8; 16]>,
+    pitch: u8,
+    memory_accesses: Vec<MemoryAccess>,
+    random: Box<dyn RandomSource>,
+    quirks: Quirks,
+}
+
+impl Default for Chip8 {
+    fn default() -> Self {
+        Self::new(Quirks::default())
+    }
 }
 
 impl Chip8 {
-    pub fn new() -> Chip8 {
+    pub fn new(quirks: Quirks) -> Chip8 {
         let mut chip8 = Chip8 {
-            memory: [0; 4096],
+            memory: vec![0; MEMORY_SIZE],
             registers: [0; 16],
             index_register: 0,
             stack: Vec::new(),
-            program_counter: 0x200, // programs start at 0x200
+            program_counter: PROGRAM_START as u16, // programs start at 0x200
             delay_timer: 0,
             sound_timer: 0,
-            vram: Vec::new(),
-            vram_changed: false,
-            vram_scale: 1,
+            vram: std::array::from_fn(|_| Framebuffer::lores()),
+            // nothing has been shown yet
+            vram_changed: true,
+            erased: false,
             awaiting_keypress: false,
             awaiting_keylift: false,
+            halt_key: 0,
+            awaiting_vblank: false,
+            hires: false,
+            halted: false,
+            rpl_flags: [0; 16],
+            selected_planes: 0b01,
+            audio_pattern: None,
+            pitch: 64, // 4000 Hz
+            memory_accesses: Vec::new(),
+            // unseeded runs differ every time, like the real thing
+            random: Box::new(SplitMix64::new(rand::random())),
+            quirks,
         };
 
         chip8.load_sprites_into_memory();
@@ -64,64 +115,285 @@ impl Chip8 {
             0xF0, 0x80, 0xF0, 0x80, 0x80, // F
         ];
 
+        // SUPER-CHIP 8x10 font for FX30, XO-CHIP adds A-F
+        let big_sprites: [u8; 160] = [
+            0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+            0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+            0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+            0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+        ];
+
         built_in_sprites
             .iter()
             .enumerate()
             .for_each(|(index, byte)| self.memory[index] = *byte);
+        self.memory[BIG_FONT_START..BIG_FONT_START + big_sprites.len()]
+            .copy_from_slice(&big_sprites);
     }
 
-    pub fn load_rom(&mut self, rom_file_path: String) -> Result<(), String> {
-        let rom_contents = fs::read(rom_file_path).expect("Error opening file");
+    /// Replaces where CXNN gets its numbers from.
+    pub fn set_random_source(&mut self, random: Box<dyn RandomSource>) {
+        self.random = random;
+    }
 
-        let mem_start_location = 0x200;
-        for index in 0..rom_contents.len() {
-            self.memory[mem_start_location + index] = rom_contents[index];
+    /// Makes CXNN produce the same numbers every run for the same seed.
+    pub fn seed_random(&mut self, seed: u64) {
+        self.set_random_source(Box::new(SplitMix64::new(seed)));
+    }
+
+    pub fn load_rom(&mut self, rom_file_path: String) -> Result<(), Chip8Error> {
+        let rom_contents = fs::read(rom_file_path)?;
+        self.load_rom_bytes(&rom_contents)
+    }
+
+    pub fn load_rom_bytes(&mut self, rom_contents: &[u8]) -> Result<(), Chip8Error> {
+        let max = self.memory.len() - PROGRAM_START;
+        if rom_contents.len() > max {
+            return Err(Chip8Error::RomTooLarge {
+                size: rom_contents.len(),
+                max,
+            });
         }
 
+        self.memory[PROGRAM_START..PROGRAM_START + rom_contents.len()]
+            .copy_from_slice(rom_contents);
+
         Ok(())
     }
 
-    pub fn initialize_pixels(&mut self, height: u32, width: u32) -> Result<(), String> {
-        println!("H: {}", height);
-        println!("W: {}", width);
-        if height % DEFAULT_CHIP8_PIXEL_HEIGHT != 0 {
-            return Err(format!("Window height is not evenly divisible by default height. Window height: {}, default height: {}", height, DEFAULT_CHIP8_PIXEL_HEIGHT));
+    /// Snapshots the whole machine into the versioned format described in `save_state`.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+
+        writer.bytes(&self.memory);
+        writer.bytes(&self.registers);
+        writer.u16(self.index_register);
+        writer.u8(self.stack.len() as u8);
+        self.stack.iter().for_each(|&address| writer.u16(address));
+        writer.u16(self.program_counter);
+        writer.u8(self.delay_timer);
+        writer.u8(self.sound_timer);
+
+        writer.bool(self.awaiting_keypress);
+        writer.bool(self.awaiting_keylift);
+        writer.u8(self.halt_key);
+        writer.bool(self.awaiting_vblank);
+        writer.bool(self.halted);
+
+        writer.bool(self.hires);
+        writer.u8(self.selected_planes);
+        writer.bytes(&self.rpl_flags);
+        writer.bool(self.audio_pattern.is_some());
+        writer.bytes(&self.audio_pattern.unwrap_or_default());
+        writer.u8(self.pitch);
+        writer.u64(self.random.state());
+
+        writer.bool(self.quirks.shift_uses_vy);
+        writer.u8(match self.quirks.load_store_index {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::ByX => 1,
+            IndexIncrement::ByXPlusOne => 2,
+        });
+        writer.bool(self.quirks.jump_uses_vx);
+        writer.bool(self.quirks.logic_resets_vf);
+        writer.bool(self.quirks.sprites_clip);
+        writer.bool(self.quirks.display_wait);
+
+        let (width, height) = (self.display_width(), self.display_height());
+        for plane in &self.vram {
+            writer.bits((0..height).flat_map(|y| (0..width).map(move |x| plane.get(x, y))));
         }
 
-        if width % DEFAULT_CHIP8_PIXEL_WIDTH != 0 {
-            return Err(format!("Window width is not evenly divisible by default width. Window width: {}, default width: {}", width, DEFAULT_CHIP8_PIXEL_WIDTH));
+        writer.finish()
+    }
+
+    /// Restores a snapshot from `save_state`. Nothing is changed if the state doesn't parse.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), Chip8Error> {
+        let mut reader = StateReader::new(state)?;
+
+        let memory = reader.bytes(MEMORY_SIZE)?.to_vec();
+        let mut registers = [0; 16];
+        registers.copy_from_slice(reader.bytes(16)?);
+        let index_register = reader.u16()?;
+        let stack_len = reader.u8()? as usize;
+        if stack_len > STACK_SIZE {
+            return Err(Chip8Error::InvalidSaveState("stack is too deep"));
         }
+        let stack = (0..stack_len)
+            .map(|_| reader.u16())
+            .collect::<Result<Vec<u16>, Chip8Error>>()?;
+        let program_counter = reader.u16()?;
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
 
-        let height_scale = height / DEFAULT_CHIP8_PIXEL_HEIGHT;
-        let width_scale = width / DEFAULT_CHIP8_PIXEL_WIDTH;
+        let awaiting_keypress = reader.bool()?;
+        let awaiting_keylift = reader.bool()?;
+        let halt_key = reader.u8()?;
+        let awaiting_vblank = reader.bool()?;
+        let halted = reader.bool()?;
 
-        if height_scale != width_scale {
-            return Err(format!("Window width scale and window height scale do not match. Width scale: {}, height scale: {}", width_scale, height_scale));
+        let hires = reader.bool()?;
+        let selected_planes = reader.u8()?;
+        let mut rpl_flags = [0; 16];
+        rpl_flags.copy_from_slice(reader.bytes(16)?);
+        let has_audio_pattern = reader.bool()?;
+        let mut audio_pattern = [0; 16];
+        audio_pattern.copy_from_slice(reader.bytes(16)?);
+        let pitch = reader.u8()?;
+        let random_state = reader.u64()?;
+
+        let quirks = Quirks {
+            shift_uses_vy: reader.bool()?,
+            load_store_index: match reader.u8()? {
+                0 => IndexIncrement::Unchanged,
+                1 => IndexIncrement::ByX,
+                2 => IndexIncrement::ByXPlusOne,
+                _ => return Err(Chip8Error::InvalidSaveState("unknown FX55/FX65 quirk")),
+            },
+            jump_uses_vx: reader.bool()?,
+            logic_resets_vf: reader.bool()?,
+            sprites_clip: reader.bool()?,
+            display_wait: reader.bool()?,
+        };
+
+        let (width, height) = if hires {
+            (HIRES_CHIP8_PIXEL_WIDTH, HIRES_CHIP8_PIXEL_HEIGHT)
+        } else {
+            (DEFAULT_CHIP8_PIXEL_WIDTH, DEFAULT_CHIP8_PIXEL_HEIGHT)
+        };
+        let mut planes = Vec::new();
+        for _ in 0..PLANE_COUNT {
+            planes.push(reader.bits((width * height) as usize)?);
         }
+        reader.finish()?;
 
-        //arbitrarily chose height scale, it and width_scale should be equal here
-        // if theyre not, we're screwed!
-        self.vram_scale = height_scale as usize;
+        self.memory = memory;
+        self.registers = registers;
+        self.index_register = index_register;
+        self.stack = stack;
+        self.program_counter = program_counter;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.awaiting_keypress = awaiting_keypress;
+        self.awaiting_keylift = awaiting_keylift;
+        self.halt_key = halt_key;
+        self.awaiting_vblank = awaiting_vblank;
+        self.halted = halted;
+        self.hires = hires;
+        self.selected_planes = selected_planes;
+        self.rpl_flags = rpl_flags;
+        self.audio_pattern = has_audio_pattern.then_some(audio_pattern);
+        self.pitch = pitch;
+        self.random.set_state(random_state);
+        self.quirks = quirks;
 
-        // 64 x 32 pixels
-        for y_location in 0..DEFAULT_CHIP8_PIXEL_HEIGHT {
-            let mut row: Vec<Pixel> = Vec::new();
-            for x_location in 0..DEFAULT_CHIP8_PIXEL_WIDTH {
-                row.push(Pixel::new(x_location, y_location, false));
+        self.resize_vram();
+        for (framebuffer, bits) in self.vram.iter_mut().zip(planes) {
+            for (pixel, on) in bits.into_iter().enumerate() {
+                framebuffer.set(pixel as u32 % width, pixel as u32 / width, on);
             }
-            self.vram.push(row);
         }
 
         Ok(())
     }
 
+    // 64 x 32 pixels, or 128 x 64 in SUPER-CHIP hi-res mode
+    fn resize_vram(&mut self) {
+        let hires = self.hires;
+        self.vram = std::array::from_fn(|_| {
+            if hires {
+                Framebuffer::hires()
+            } else {
+                Framebuffer::lores()
+            }
+        });
+        self.vram_changed = true;
+    }
+
+    // the XO-CHIP colour index of a pixel, plane 0 is bit 0 and plane 1 is bit 1
+    pub fn pixel_color(&self, x: usize, y: usize) -> u8 {
+        self.vram
+            .iter()
+            .enumerate()
+            .map(|(plane, framebuffer)| (framebuffer.get(x as u32, y as u32) as u8) << plane)
+            .sum()
+    }
+
+    // the planes picked by FN01, as indices into vram
+    fn selected_planes(&self) -> impl Iterator<Item = usize> {
+        let selected_planes = self.selected_planes;
+        (0..PLANE_COUNT).filter(move |plane| selected_planes & (1 << plane) != 0)
+    }
+
+    pub fn display_width(&self) -> u32 {
+        if self.hires {
+            HIRES_CHIP8_PIXEL_WIDTH
+        } else {
+            DEFAULT_CHIP8_PIXEL_WIDTH
+        }
+    }
+
+    pub fn display_height(&self) -> u32 {
+        if self.hires {
+            HIRES_CHIP8_PIXEL_HEIGHT
+        } else {
+            DEFAULT_CHIP8_PIXEL_HEIGHT
+        }
+    }
+
+    // set by 00FD, the ROM asked to exit
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
     pub fn set_register_value(&mut self, register: u8, value: u8) {
         self.registers[register as usize] = value;
     }
 
+    pub fn get_registers(&self) -> &[u8; 16] {
+        &self.registers
+    }
+
+    pub fn get_index_register(&self) -> u16 {
+        self.index_register
+    }
+
+    pub fn get_program_counter(&self) -> u16 {
+        self.program_counter
+    }
+
+    pub fn get_delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn get_stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    pub fn get_memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    // for debugger watchpoints
+    pub fn last_memory_accesses(&self) -> &[MemoryAccess] {
+        &self.memory_accesses
+    }
+
     pub fn decrement_sound_timer(&mut self) {
         if self.sound_timer != 0 {
-            self.sound_timer = self.sound_timer - 1;
+            self.sound_timer -= 1;
         }
     }
 
@@ -129,331 +401,1007 @@ impl Chip8 {
         self.sound_timer
     }
 
+    // the 128 bit XO-CHIP sample loaded by F002, or None for the plain beep
+    pub fn audio_pattern(&self) -> Option<[u8; 16]> {
+        self.audio_pattern
+    }
+
+    // bits of the audio pattern played per second, as set by FX3A
+    pub fn audio_pattern_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
     pub fn play_sound(&self) {
         //play sound
         //println!("Playing sound");
     }
 
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// True if the last thing drawn erased something: the screen was cleared or a
+    /// sprite turned pixels off, as games do to a sprite before drawing it somewhere else.
+    /// A frame that ends here would show the sprite missing.
+    pub fn is_mid_redraw(&self) -> bool {
+        self.erased
+    }
+
+    // called by the frontend once per 60 Hz frame, releases a DXYN held by the display wait quirk
+    pub fn vblank(&mut self) {
+        self.awaiting_vblank = false;
+    }
+
+    /// Everything that happens once per 60 Hz frame: both timers tick down and vblank arrives.
+    pub fn end_frame(&mut self) {
+        self.decrement_sound_timer();
+        self.decrement_delay_timer();
+        self.vblank();
+    }
+
     pub fn decrement_delay_timer(&mut self) {
         if self.delay_timer != 0 {
-            self.delay_timer = self.delay_timer - 1;
+            self.delay_timer -= 1;
         }
     }
 
-    fn decode(left_byte: u8, right_byte: u8) -> u16 {
-        let left_byte = left_byte as u16;
-        let right_byte = right_byte as u16;
+    fn skip_next_instruction_if(&mut self, condition: bool) {
+        if condition {
+            // F000 NNNN is four bytes long, skipping it has to clear both halves
+            let next = self.program_counter as usize + 2;
+            let long_load = self.memory.get(next..next + 2) == Some(&[0xF0, 0x00]);
+            // the end of memory wraps around to 0, like the fetch
+            let step = if long_load { 4 } else { 2 };
+            self.program_counter = self.program_counter.wrapping_add(step);
+        }
+    }
+
+    fn draw_sprite(&mut self, x_index: usize, y_index: usize, n: u8) -> Result<(), Chip8Error> {
+        let (sprite_width, sprite_height) = if n == 0 { (16, 16) } else { (8, n as u32) };
+        let bytes_per_row = (sprite_width / 8) as usize;
+        let sprite_len = bytes_per_row * sprite_height as usize;
+        let planes: Vec<usize> = self.selected_planes().collect();
+        // with both planes selected the plane 1 sprite follows the plane 0 one
+        let sprite = self.read_range(self.index_register as usize, sprite_len * planes.len())?;
+        let width = self.display_width();
+        let height = self.display_height();
 
-        (left_byte << 8) | right_byte
+        self.registers[0x0F] = 0;
+        // the starting position always wraps, only the sprite itself is clipped
+        let x_location = self.registers[x_index] as u32 % width;
+        let y_location = self.registers[y_index] as u32 % height;
+
+        for (plane_offset, plane) in planes.into_iter().enumerate() {
+            let plane_start = sprite.start + plane_offset * sprite_len;
+            self.draw_plane_sprite(
+                plane,
+                plane_start,
+                (x_location, y_location),
+                (sprite_width, sprite_height),
+            );
+        }
+
+        self.vram_changed = true;
+        self.erased = self.registers[0x0F] == 1;
+        Ok(())
     }
 
-    pub fn handle_next_instruction(&mut self, keyboard: &mut Keyboard, event_pump: &mut EventPump) {
-        let instruction = Chip8::decode(
-            self.memory[self.program_counter as usize],
-            self.memory[self.program_counter as usize + 1],
-        );
-        //println!("{:#x}", instruction);
-        let x_index = ((instruction & 0x0F00) >> 8) as usize;
-        let y_index = ((instruction & 0x00F0) >> 4) as usize;
-        let nnn = instruction & 0x0FFF; // for instructions like ANNN, BNNN, etc.
-        let nn = (instruction & 0x00FF) as u8; // for instruction like 6XNN, 7XNN, etc.
-        let n = (instruction & 0x000F) as u8; // for instructions like DXYN
+    fn draw_plane_sprite(
+        &mut self,
+        plane: usize,
+        sprite_start: usize,
+        (x_location, y_location): (u32, u32),
+        (sprite_width, sprite_height): (u32, u32),
+    ) {
+        let bytes_per_row = (sprite_width / 8) as usize;
+        let clip = self.quirks.sprites_clip;
+        let vram = &mut self.vram[plane];
+
+        for row_offset in 0..sprite_height {
+            let row_start = sprite_start + row_offset as usize * bytes_per_row;
+            let row_bits = self.memory[row_start..row_start + bytes_per_row]
+                .iter()
+                .fold(0u16, |bits, byte| (bits << 8) | *byte as u16);
+            if vram.draw_row(
+                x_location,
+                y_location + row_offset,
+                row_bits,
+                sprite_width,
+                clip,
+            ) {
+                self.registers[0x0F] = 1;
+            }
+        }
+    }
+
+    // moves the selected planes by (dx, dy), pixels scrolled in from the edge are off
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        let planes: Vec<usize> = self.selected_planes().collect();
+        for plane in planes {
+            self.vram[plane].scroll(dx, dy);
+        }
+
+        self.vram_changed = true;
+    }
+
+    // Vx to Vy inclusive, in descending order when x > y, as used by 5XY2/5XY3
+    fn register_range(x_index: usize, y_index: usize) -> Vec<usize> {
+        if x_index <= y_index {
+            (x_index..=y_index).collect()
+        } else {
+            (y_index..=x_index).rev().collect()
+        }
+    }
+
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.registers[0x0F] = 0;
+        }
+    }
+
+    fn shift_source(&self, x_index: usize, y_index: usize) -> u8 {
+        if self.quirks.shift_uses_vy {
+            self.registers[y_index]
+        } else {
+            self.registers[x_index]
+        }
+    }
+
+    fn increment_index_after_load_store(&mut self, x_index: usize) {
+        let increment = match self.quirks.load_store_index {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::ByX => x_index as u16,
+            IndexIncrement::ByXPlusOne => x_index as u16 + 1,
+        };
+        self.index_register = self.index_register.wrapping_add(increment);
+    }
+
+    fn read_range(
+        &mut self,
+        start: usize,
+        len: usize,
+    ) -> Result<std::ops::Range<usize>, Chip8Error> {
+        let range = self.memory_range(start, len)?;
+        self.memory_accesses.push(MemoryAccess::Read(range.clone()));
+        Ok(range)
+    }
+
+    fn write_range(
+        &mut self,
+        start: usize,
+        len: usize,
+    ) -> Result<std::ops::Range<usize>, Chip8Error> {
+        let range = self.memory_range(start, len)?;
+        self.memory_accesses
+            .push(MemoryAccess::Write(range.clone()));
+        Ok(range)
+    }
+
+    fn memory_range(&self, start: usize, len: usize) -> Result<std::ops::Range<usize>, Chip8Error> {
+        if start + len > self.memory.len() {
+            return Err(Chip8Error::MemoryOutOfBounds {
+                addr: start + len - 1,
+            });
+        }
+
+        Ok(start..start + len)
+    }
+
+    pub fn handle_next_instruction(&mut self, keypad: &impl Keypad) -> Result<(), Chip8Error> {
+        self.memory_accesses.clear();
+        if self.awaiting_vblank || self.halted {
+            return Ok(());
+        }
+
+        let pc = self.program_counter;
+        let fetch = self.memory_range(pc as usize, 2)?;
+        let instruction = Instruction::decode(&self.memory[fetch.start..]).ok_or_else(|| {
+            Chip8Error::InvalidOpcode {
+                pc,
+                opcode: u16::from_be_bytes([
+                    self.memory[fetch.start],
+                    self.memory[fetch.start + 1],
+                ]),
+            }
+        })?;
+
+        self.execute(instruction, keypad)
+    }
+
+    /// Runs an already decoded instruction as if it had been fetched from the program counter.
+    pub fn execute(
+        &mut self,
+        instruction: Instruction,
+        keypad: &impl Keypad,
+    ) -> Result<(), Chip8Error> {
+        let pc = self.program_counter;
         let mut increment_program_counter = true;
 
-        match instruction >> 12 {
-            0x0 => match instruction & 0x00FF {
-                0x00E0 => {
-                    // ("clear diplay");
-                    self.vram.iter_mut().for_each(|row| {
-                        row.iter_mut().for_each(|pixel| {
-                            pixel.turn_off();
-                        });
-                    });
-                    self.vram_changed = true;
-                }
-                0x00EE => {
-                    //  ("return");
-                    self.program_counter = self.stack.pop().unwrap();
+        match instruction {
+            Instruction::Sys(_) => {
+                // ("0NNN: calls a machine code routine on the COSMAC VIP, interpreters skip it");
+            }
+            Instruction::Cls => {
+                // ("clear diplay"), only the selected planes on XO-CHIP
+                let planes: Vec<usize> = self.selected_planes().collect();
+                for plane in planes {
+                    self.vram[plane].clear();
                 }
-                _ => {}
-            },
-            0x1 => {
+                self.vram_changed = true;
+                self.erased = true;
+            }
+            Instruction::Ret => {
+                //  ("return");
+                self.program_counter = self.stack.pop().ok_or(Chip8Error::StackUnderflow { pc })?;
+            }
+            Instruction::ScrollDown(n) => {
+                // ("00CN: scrolls the display down by N pixels");
+                self.scroll(0, n as i32);
+            }
+            Instruction::ScrollUp(n) => {
+                // ("00DN: XO-CHIP, scrolls the display up by N pixels");
+                self.scroll(0, -(n as i32));
+            }
+            Instruction::ScrollRight => {
+                // ("00FB: scrolls the display right by 4 pixels");
+                self.scroll(4, 0);
+            }
+            Instruction::ScrollLeft => {
+                // ("00FC: scrolls the display left by 4 pixels");
+                self.scroll(-4, 0);
+            }
+            Instruction::Exit => {
+                // ("00FD: exits the interpreter");
+                self.halted = true;
+                increment_program_counter = false;
+            }
+            Instruction::LowRes => {
+                // ("00FE: switches to 64x32 low-res mode, clearing the display");
+                self.hires = false;
+                self.resize_vram();
+            }
+            Instruction::HighRes => {
+                // ("00FF: switches to 128x64 hi-res mode, clearing the display");
+                self.hires = true;
+                self.resize_vram();
+            }
+            Instruction::Jp(nnn) => {
                 // ("goto NNN");
                 self.program_counter = nnn;
                 increment_program_counter = false;
             }
-            0x2 => {
+            Instruction::Call(nnn) => {
                 // ("call subroutine at NNN");
+                if self.stack.len() >= STACK_SIZE {
+                    return Err(Chip8Error::StackOverflow { pc });
+                }
                 self.stack.push(self.program_counter);
                 self.program_counter = nnn;
                 increment_program_counter = false;
             }
-            0x3 => {
+            Instruction::SeImm { x, nn } => {
                 // ("conditional, 3XNN: skips next instruction if Vx = NN");
-                if self.registers[x_index] == nn {
-                    self.program_counter = self.program_counter + 2;
-                }
+                self.skip_next_instruction_if(self.registers[x as usize] == nn);
             }
-            0x4 => {
+            Instruction::SneImm { x, nn } => {
                 // ("conditional, 4XNN: skips next instruction if Vx != NN");
-                if self.registers[x_index] != nn {
-                    self.program_counter = self.program_counter + 2;
-                }
+                self.skip_next_instruction_if(self.registers[x as usize] != nn);
             }
-            0x5 => {
+            Instruction::SeReg { x, y } => {
                 // ("conditional, 5XY0: skips next instruction if Vx == Vy");
-                if self.registers[x_index] == self.registers[y_index] {
-                    self.program_counter = self.program_counter + 2;
+                self.skip_next_instruction_if(
+                    self.registers[x as usize] == self.registers[y as usize],
+                );
+            }
+            Instruction::SaveRange { x, y } => {
+                // ("5XY2: XO-CHIP, stores Vx to Vy (inclusive, in either direction) in memory starting at I. I is left unmodified");
+                let registers = Chip8::register_range(x as usize, y as usize);
+                let save = self.write_range(self.index_register as usize, registers.len())?;
+                for (address, register) in save.zip(registers) {
+                    self.memory[address] = self.registers[register];
+                }
+            }
+            Instruction::LoadRange { x, y } => {
+                // ("5XY3: XO-CHIP, fills Vx to Vy (inclusive, in either direction) from memory starting at I. I is left unmodified");
+                let registers = Chip8::register_range(x as usize, y as usize);
+                let load = self.read_range(self.index_register as usize, registers.len())?;
+                for (address, register) in load.zip(registers) {
+                    self.registers[register] = self.memory[address];
                 }
             }
-            0x6 => {
+            Instruction::LdImm { x, nn } => {
                 // ("6XNN: sets Vx to NN");
-                self.registers[x_index] = nn;
+                self.registers[x as usize] = nn;
             }
-            0x7 => {
+            Instruction::AddImm { x, nn } => {
                 // ("7XNN: adds NN to Vx (carry flag not changed)");
-                self.registers[x_index] = self.registers[x_index].wrapping_add(nn);
+                self.registers[x as usize] = self.registers[x as usize].wrapping_add(nn);
             }
-            0x8 => match instruction & 0x000F {
-                0x0 => {
-                    // ("8XY0: sets Vx to Vy");
-                    self.registers[x_index] = self.registers[y_index];
-                }
-                0x1 => {
-                    // ("8XY1: sets Vx to Vx | Vy. Vx = Vx | Vy");
-                    self.registers[x_index] = self.registers[x_index] | self.registers[y_index];
-                }
-                0x2 => {
-                    // ("8XY2: sets Vx to Vx & Vy. Vx = Vx & Vy");
-                    self.registers[x_index] = self.registers[x_index] & self.registers[y_index];
-                }
-                0x3 => {
-                    // ("8XY3: sets Vx to Vx xor Vy. Vx = Vx ^ Vy");
-                    self.registers[x_index] = self.registers[x_index] ^ self.registers[y_index];
-                }
-                0x4 => {
-                    // ("8XY4: Adds Vy to Vx. VF(carry flag) is set to 1 when there's an overflow, and to 0 when there is not");
-                    match self.registers[x_index].checked_add(self.registers[y_index]) {
-                        Some(output) => {
-                            self.registers[x_index] = output;
-                            self.registers[0x0F] = 0;
-                        }
-                        None => {
-                            self.registers[x_index] =
-                                self.registers[x_index].wrapping_add(self.registers[y_index]);
-                            self.registers[0x0F] = 1;
-                        }
+            Instruction::LdReg { x, y } => {
+                // ("8XY0: sets Vx to Vy");
+                self.registers[x as usize] = self.registers[y as usize];
+            }
+            Instruction::Or { x, y } => {
+                // ("8XY1: sets Vx to Vx | Vy. Vx = Vx | Vy");
+                self.registers[x as usize] |= self.registers[y as usize];
+                self.reset_vf_after_logic();
+            }
+            Instruction::And { x, y } => {
+                // ("8XY2: sets Vx to Vx & Vy. Vx = Vx & Vy");
+                self.registers[x as usize] &= self.registers[y as usize];
+                self.reset_vf_after_logic();
+            }
+            Instruction::Xor { x, y } => {
+                // ("8XY3: sets Vx to Vx xor Vy. Vx = Vx ^ Vy");
+                self.registers[x as usize] ^= self.registers[y as usize];
+                self.reset_vf_after_logic();
+            }
+            Instruction::AddReg { x, y } => {
+                // ("8XY4: Adds Vy to Vx. VF(carry flag) is set to 1 when there's an overflow, and to 0 when there is not");
+                let (x_index, y_index) = (x as usize, y as usize);
+                match self.registers[x_index].checked_add(self.registers[y_index]) {
+                    Some(output) => {
+                        self.registers[x_index] = output;
+                        self.registers[0x0F] = 0;
                     }
-                }
-                0x5 => {
-                    // ("8XY5: Vy is subtracted from Vx. VF (carry flag) is set to 0 when there is an underflow, and 1 when there is not. (VF = 1 if Vx >= Vy and 0 if not)");
-                    match self.registers[x_index].checked_sub(self.registers[y_index]) {
-                        Some(output) => {
-                            self.registers[x_index] = output;
-                            self.registers[0x0F] = 1;
-                        }
-                        None => {
-                            self.registers[x_index] =
-                                self.registers[x_index].wrapping_sub(self.registers[y_index]);
-                            self.registers[0x0F] = 0;
-                        }
+                    None => {
+                        self.registers[x_index] =
+                            self.registers[x_index].wrapping_add(self.registers[y_index]);
+                        self.registers[0x0F] = 1;
                     }
                 }
-                0x6 => {
-                    //                     ("8XY6: stores to least significant bit of Vx in VF and then shifts Vx to the right by 1. Vx = Vx >> 1");
-
-                    let least_sig_bit = self.registers[x_index] & 0x01;
-                    self.registers[x_index] = self.registers[x_index] >> 1;
-                    self.registers[0x0F] = least_sig_bit;
-                }
-                0x7 => {
-                    // ("8XY7: sets Vx to Vy minus Vx. Vf is set to 0 when there is an underflow, and 1 when there is not. i.e. VF = 1 when Vy >= Vx");
-                    match self.registers[y_index].checked_sub(self.registers[x_index]) {
-                        Some(output) => {
-                            self.registers[x_index] = output;
-                            self.registers[0x0F] = 1;
-                        }
-                        None => {
-                            self.registers[x_index] =
-                                self.registers[y_index].wrapping_sub(self.registers[x_index]);
-                            self.registers[0x0F] = 0;
-                        }
+            }
+            Instruction::Sub { x, y } => {
+                // ("8XY5: Vy is subtracted from Vx. VF (carry flag) is set to 0 when there is an underflow, and 1 when there is not. (VF = 1 if Vx >= Vy and 0 if not)");
+                let (x_index, y_index) = (x as usize, y as usize);
+                match self.registers[x_index].checked_sub(self.registers[y_index]) {
+                    Some(output) => {
+                        self.registers[x_index] = output;
+                        self.registers[0x0F] = 1;
+                    }
+                    None => {
+                        self.registers[x_index] =
+                            self.registers[x_index].wrapping_sub(self.registers[y_index]);
+                        self.registers[0x0F] = 0;
                     }
                 }
-                0xE => {
-                    // ("8XYE: stores the most significant bit in VF and shifts VX to the left by 1. Vx = Vx << 1");
-                    let most_sig_bit = (self.registers[x_index] & 0b1000_0000) >> 7;
-                    self.registers[x_index] = self.registers[x_index] << 1;
-                    self.registers[0x0F] = most_sig_bit;
+            }
+            Instruction::Shr { x, y } => {
+                // ("8XY6: stores to least significant bit of Vx in VF and then shifts Vx to the right by 1. Vx = Vx >> 1");
+                let source = self.shift_source(x as usize, y as usize);
+                let least_sig_bit = source & 0x01;
+                self.registers[x as usize] = source >> 1;
+                self.registers[0x0F] = least_sig_bit;
+            }
+            Instruction::Subn { x, y } => {
+                // ("8XY7: sets Vx to Vy minus Vx. Vf is set to 0 when there is an underflow, and 1 when there is not. i.e. VF = 1 when Vy >= Vx");
+                let (x_index, y_index) = (x as usize, y as usize);
+                match self.registers[y_index].checked_sub(self.registers[x_index]) {
+                    Some(output) => {
+                        self.registers[x_index] = output;
+                        self.registers[0x0F] = 1;
+                    }
+                    None => {
+                        self.registers[x_index] =
+                            self.registers[y_index].wrapping_sub(self.registers[x_index]);
+                        self.registers[0x0F] = 0;
+                    }
                 }
-                _ => println!("invalide opcode"),
-            },
-            0x9 => {
+            }
+            Instruction::Shl { x, y } => {
+                // ("8XYE: stores the most significant bit in VF and shifts VX to the left by 1. Vx = Vx << 1");
+                let source = self.shift_source(x as usize, y as usize);
+                let most_sig_bit = (source & 0b1000_0000) >> 7;
+                self.registers[x as usize] = source << 1;
+                self.registers[0x0F] = most_sig_bit;
+            }
+            Instruction::SneReg { x, y } => {
                 // ("9XY0: skips the next instruction if Vx != Vy");
-                if self.registers[x_index] != self.registers[y_index] {
-                    self.program_counter = self.program_counter + 2;
-                }
+                self.skip_next_instruction_if(
+                    self.registers[x as usize] != self.registers[y as usize],
+                );
             }
-            0xA => {
+            Instruction::LdI(nnn) => {
                 // ("ANNN: Sets the I(instruction) address to NNN");
                 self.index_register = nnn;
-                //increment_program_counter = false;
             }
-            0xB => {
+            Instruction::JpOffset(nnn) => {
                 // ("BNNN: jumps to the address NNN plus V0. PC(program counter) = V0 + NNN");
-                self.program_counter = self.registers[0x00] as u16 + nnn;
+                // ("BXNN with the jump quirk: jumps to XNN plus Vx");
+                let offset_register = if self.quirks.jump_uses_vx {
+                    (nnn >> 8) as usize
+                } else {
+                    0x0
+                };
+                self.program_counter = self.registers[offset_register] as u16 + nnn;
+                increment_program_counter = false;
             }
-            0xC => {
+            Instruction::Rnd { x, nn } => {
                 // ("CXNN: sets Vx to the result of a bitwise and operation on a random number (typically 0 to 255) and NN. Vx = rand() & NN");
-                let random_number: u8 = rand::thread_rng().gen();
-                self.registers[x_index] = random_number & nn;
+                let random_number = self.random.next_byte();
+                self.registers[x as usize] = random_number & nn;
             }
-            0xD => {
+            Instruction::Drw { x, y, n } => {
                 // ("DXYN: Draws a sprite at coordinate (VX, VY) that has a width of 8 pixels and a height of N pixels. Each row of 8 pixels is read as bit-coded starting from memory location I; I value does not change after the execution of this instruction. As described above, VF is set to 1 if any screen pixels are flipped from set to unset when the sprite is drawn, and to 0 if that does not happen");
-                //let ip = self.program_counter as usize;
-                self.registers[0x0F] = 0;
-                let x_location = self.registers[x_index];
-                let y_location = self.registers[y_index];
-                let index = self.index_register as usize;
-                if index + n as usize >= self.memory.len() - 1 {
-                    return;
-                }
-
-                for row_offset in 0..n as u32 {
-                    let row_byte = self.memory[index + row_offset as usize];
-                    for column_offset in 0..8 {
-                        let bit_shift_amount = 7 - column_offset;
-                        let and_val = 0b1000_0000 >> column_offset;
-                        let pixel_val = ((row_byte & and_val) >> bit_shift_amount) == 1;
-
-                        let y_wrapped = ((y_location as u32 + row_offset)
-                            % DEFAULT_CHIP8_PIXEL_HEIGHT)
-                            as usize;
-                        let x_wrapped = ((x_location as u32 + column_offset)
-                            % DEFAULT_CHIP8_PIXEL_WIDTH)
-                            as usize;
-                        if pixel_val && self.vram[y_wrapped][x_wrapped].on {
-                            self.registers[0x0F] = 1;
-                            self.vram[y_wrapped][x_wrapped].turn_off();
-                        } else if pixel_val && !self.vram[y_wrapped][x_wrapped].on {
-                            self.vram[y_wrapped][x_wrapped].set(pixel_val);
-                        }
-                    }
-                }
-
-                self.vram_changed = true;
+                // ("DXY0: in SUPER-CHIP, draws a 16x16 sprite read as two bytes per row");
+                self.draw_sprite(x as usize, y as usize, n)?;
+                self.awaiting_vblank = self.quirks.display_wait;
             }
-            0xE => match instruction & 0x00F0 {
-                0x0090 => {
-                    // ("EX9E: skips the next instruction if the key stored in Vx is pressed (usually the next instruction is a jump to skip a code block). if(key() == Vx)");
-                    let key = self.registers[x_index];
-
-                    if key < 16 && keyboard.get_state()[key as usize] {
-                        self.program_counter = self.program_counter + 2;
-                    }
-                }
-                0x00A0 => {
-                    // ("EXA1: skips the next instruction if the key stored in Vx is not pressed (usually the next instruction is a jump to skip a code block. if (key() != Vx))");
-                    let key = self.registers[x_index];
-                    if key < 16 && !keyboard.get_state()[key as usize] {
-                        self.program_counter = self.program_counter + 2;
+            Instruction::Skp(x) => {
+                // ("EX9E: skips the next instruction if the key stored in Vx is pressed (usually the next instruction is a jump to skip a code block). if(key() == Vx)");
+                let key = self.registers[x as usize];
+                self.skip_next_instruction_if(keypad.is_key_pressed(key));
+            }
+            Instruction::Sknp(x) => {
+                // ("EXA1: skips the next instruction if the key stored in Vx is not pressed (usually the next instruction is a jump to skip a code block. if (key() != Vx))");
+                // keys past F are never pressed
+                let key = self.registers[x as usize];
+                self.skip_next_instruction_if(!keypad.is_key_pressed(key));
+            }
+            Instruction::LdILong(nnnn) => {
+                // ("F000 NNNN: XO-CHIP, sets I to the 16 bit address in the following two bytes");
+                self.index_register = nnnn;
+            }
+            Instruction::LdAudio => {
+                // ("F002: XO-CHIP, loads the 16 byte audio pattern from memory starting at I");
+                let pattern = self.read_range(self.index_register as usize, 16)?;
+                let mut audio_pattern = [0; 16];
+                audio_pattern.copy_from_slice(&self.memory[pattern]);
+                self.audio_pattern = Some(audio_pattern);
+            }
+            Instruction::Plane(n) => {
+                // ("FN01: XO-CHIP, selects the bitplanes N that drawing, clearing and scrolling affect");
+                self.selected_planes = n & 0b11;
+            }
+            Instruction::LdVxDt(x) => {
+                // ("FX07: sets vx to the value of the delay timer. Vx = get_delay()");
+                self.registers[x as usize] = self.delay_timer;
+            }
+            Instruction::LdKey(x) => {
+                // ("FX0A: A key press is awaited, and then stored in Vx (blocking operation, all instruction halted until next key event. probably a loop?)");
+                // the key is only stored once it's been released again, otherwise a
+                // single press would satisfy several FX0As in a row
+                if self.awaiting_keylift {
+                    if !keypad.is_key_pressed(self.halt_key) {
+                        self.awaiting_keylift = false;
+                        self.registers[x as usize] = self.halt_key;
                     }
-                }
-                _ => {
-                    ("invalid opcode");
-                }
-            },
-            0xF => match instruction & 0x00FF {
-                0x0007 => {
-                    // ("FX07: sets vx to the value of the delay timer. Vx = get_delay()");
-                    self.registers[x_index] = self.delay_timer;
-                }
-                0x000A => {
-                    // ("FX0A: A key press is awaited, and then stored in Vx (blocking operation, all instruction halted until next key event. probably a loop?)");
+                } else if let Some(key) = (0..16).find(|&key| keypad.is_key_pressed(key)) {
+                    self.halt_key = key;
+                    self.awaiting_keylift = true;
+                    self.awaiting_keypress = false;
+                } else {
                     self.awaiting_keypress = true;
-                    if self.awaiting_keypress {
-                        if let Some(key) = keyboard.get_state().iter().position(|&state| state) {
-                            keyboard.set_halt_key(key as u8);
-                            self.awaiting_keylift = true;
-                            self.awaiting_keypress = false;
-                        }
-                    }
-
-                    if self.awaiting_keylift {
-                        if !keyboard.get_state()[keyboard.get_halt_key()] {
-                            self.awaiting_keylift = false;
-                            self.registers[x_index] = keyboard.get_halt_key() as u8;
-                        }
-                    }
-                    self.awaiting_keypress =
-                        self.registers[x_index] != keyboard.get_halt_key() as u8;
-
-                    if self.awaiting_keypress || self.awaiting_keylift {
-                        self.program_counter = self.program_counter - 2;
-                    }
                 }
-                0x0015 => {
-                    // ("FX15: sets the delay timer to Vx. delay_timer(Vx)");
-                    self.delay_timer = self.registers[x_index];
-                }
-                0x0018 => {
-                    // ("FX18: sets the sound timer to Vx. sound_timer(Vx);")
-                    self.sound_timer = self.registers[x_index];
-                }
-                0x001E => {
-                    // ("FX1E: Adds Vx to I. VF is not affected. I = I + Vx");
-                    self.index_register = self.index_register + self.registers[x_index] as u16;
-                }
-                0x0029 => {
-                    // ("FX29: sets I to the location of the sprite for the character in Vx. characters 0-F in hex are represented by a 4x5 font. I = sprite_addr[Vx]");
 
-                    if let Some(sprite_addr) = self.registers[x_index].checked_mul(5) {
-                        self.index_register = sprite_addr as u16;
-                    }
-                }
-                0x0033 => {
-                    // ("FX33: stores the binary-codeddecimal representation of Vx, with the hundreds digit in memory at location I, the tens digit at location I+1, and the ones digit at locaion I + 2");
-                    let register_x_val = self.registers[x_index];
-                    let hundreds = (register_x_val / 100) % 10;
-                    let tens = (register_x_val / 10) % 10;
-                    let ones = register_x_val % 10;
-                    let index = self.index_register as usize;
-
-                    if !(index >= self.memory.len() - 2) {
-                        self.memory[index] = hundreds;
-                        self.memory[index + 1] = tens;
-                        self.memory[index + 2] = ones;
-                    }
-                }
-                0x0055 => {
-                    // ("FX55: stores from V0 to Vx (including Vx) in memory, starting at address I. the offset from I is increased by 1 for each value written, but I itself is left unmodified. reg_dum(Vx, &I)");
-                    let i = self.index_register as usize;
-                    self.registers
-                        .iter()
-                        .take(x_index + 1) //+1 bc zero index
-                        .enumerate()
-                        .for_each(|(index, register)| {
-                            self.memory[i + index] = *register;
-                        });
+                if self.awaiting_keypress || self.awaiting_keylift {
+                    increment_program_counter = false;
                 }
-                0x0065 => {
-                    // ("FX65: Fills from V0 to Vx (including Vx) with values from memory, starting at address I. the offset from I is increased by 1 for each value read, but I remains umodified.");
-                    let i = self.index_register as usize;
-                    if !(i >= self.memory.len() - 2) {
-                        let mem_slice = &self.memory[i..i + x_index + 1];
-
-                        mem_slice.iter().enumerate().for_each(|(index, mem_val)| {
-                            self.registers[index] = *mem_val;
-                        });
-                    }
+            }
+            Instruction::LdDtVx(x) => {
+                // ("FX15: sets the delay timer to Vx. delay_timer(Vx)");
+                self.delay_timer = self.registers[x as usize];
+            }
+            Instruction::LdStVx(x) => {
+                // ("FX18: sets the sound timer to Vx. sound_timer(Vx);")
+                self.sound_timer = self.registers[x as usize];
+            }
+            Instruction::AddI(x) => {
+                // ("FX1E: Adds Vx to I. VF is not affected. I = I + Vx");
+                self.index_register = self
+                    .index_register
+                    .wrapping_add(self.registers[x as usize] as u16);
+            }
+            Instruction::LdFont(x) => {
+                // ("FX29: sets I to the location of the sprite for the character in Vx. characters 0-F in hex are represented by a 4x5 font. I = sprite_addr[Vx]");
+
+                if let Some(sprite_addr) = self.registers[x as usize].checked_mul(5) {
+                    self.index_register = sprite_addr as u16;
                 }
-                _ => {}
-            },
-            _ => (),
+            }
+            Instruction::LdBigFont(x) => {
+                // ("FX30: sets I to the location of the 8x10 big font sprite for the character in Vx");
+                self.index_register =
+                    (BIG_FONT_START + (self.registers[x as usize] & 0x0F) as usize * 10) as u16;
+            }
+            Instruction::Bcd(x) => {
+                // ("FX33: stores the binary-codeddecimal representation of Vx, with the hundreds digit in memory at location I, the tens digit at location I+1, and the ones digit at locaion I + 2");
+                let register_x_val = self.registers[x as usize];
+                let hundreds = (register_x_val / 100) % 10;
+                let tens = (register_x_val / 10) % 10;
+                let ones = register_x_val % 10;
+                let bcd = self.write_range(self.index_register as usize, 3)?;
+                self.memory[bcd].copy_from_slice(&[hundreds, tens, ones]);
+            }
+            Instruction::LdPitch(x) => {
+                // ("FX3A: XO-CHIP, sets the audio pattern pitch register to Vx");
+                self.pitch = self.registers[x as usize];
+            }
+            Instruction::StoreRegs(x) => {
+                // ("FX55: stores from V0 to Vx (including Vx) in memory, starting at address I. the offset from I is increased by 1 for each value written, but I itself is left unmodified. reg_dum(Vx, &I)");
+                let x_index = x as usize;
+                let dump = self.write_range(self.index_register as usize, x_index + 1)?; //+1 bc zero index
+                self.memory[dump].copy_from_slice(&self.registers[..=x_index]);
+                self.increment_index_after_load_store(x_index);
+            }
+            Instruction::LoadRegs(x) => {
+                // ("FX65: Fills from V0 to Vx (including Vx) with values from memory, starting at address I. the offset from I is increased by 1 for each value read, but I remains umodified.");
+                let x_index = x as usize;
+                let load = self.read_range(self.index_register as usize, x_index + 1)?;
+                self.registers[..=x_index].copy_from_slice(&self.memory[load]);
+                self.increment_index_after_load_store(x_index);
+            }
+            Instruction::StoreFlags(x) => {
+                // ("FX75: stores V0 to Vx (including Vx) in the RPL user flags");
+                let x_index = x as usize;
+                self.rpl_flags[..=x_index].copy_from_slice(&self.registers[..=x_index]);
+            }
+            Instruction::LoadFlags(x) => {
+                // ("FX85: fills V0 to Vx (including Vx) from the RPL user flags");
+                let x_index = x as usize;
+                self.registers[..=x_index].copy_from_slice(&self.rpl_flags[..=x_index]);
+            }
         };
 
         if increment_program_counter {
-            self.program_counter = self.program_counter + 2;
+            self.program_counter = self.program_counter.wrapping_add(instruction.size());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn chip8_with_program(program: &[u8]) -> Chip8 {
+        chip8_with_quirks(program, Quirks::default())
+    }
+
+    fn chip8_with_quirks(program: &[u8], quirks: Quirks) -> Chip8 {
+        let mut chip8 = Chip8::new(quirks);
+        chip8.memory[0x200..0x200 + program.len()].copy_from_slice(program);
+        chip8
+    }
+
+    #[test]
+    fn skip_if_key_pressed_test() {
+        // V0 = 5, EX9E on V0
+        let mut chip8 = chip8_with_program(&[0x60, 0x05, 0xE0, 0x9E]);
+        let mut keypad = [false; 16];
+        keypad[5] = true;
+
+        chip8.handle_next_instruction(&keypad).unwrap();
+        chip8.handle_next_instruction(&keypad).unwrap();
+
+        assert_eq!(0x206, chip8.program_counter);
+    }
+
+    #[test]
+    fn skip_long_load_on_key_test() {
+        // V0 = 5, EX9E then F000 NNNN, which has to be stepped over whole
+        let program = [0x60, 0x05, 0xE0, 0x9E, 0xF0, 0x00, 0x12, 0x34];
+        let mut chip8 = chip8_with_program(&program);
+        let mut keypad = [false; 16];
+        keypad[5] = true;
+
+        chip8.handle_next_instruction(&keypad).unwrap();
+        chip8.handle_next_instruction(&keypad).unwrap();
+        assert_eq!(0x208, chip8.program_counter);
+
+        // V0 = 0x20, EXA1 skips since there's no key 0x20 to press
+        let mut chip8 = chip8_with_program(&[0x60, 0x20, 0xE0, 0xA1]);
+        chip8.handle_next_instruction(&keypad).unwrap();
+        chip8.handle_next_instruction(&keypad).unwrap();
+        assert_eq!(0x206, chip8.program_counter);
+    }
+
+    #[test]
+    fn wait_for_key_release_test() {
+        // FX0A blocks until a key is pressed and then released
+        let mut chip8 = chip8_with_program(&[0xF3, 0x0A]);
+        let mut keypad = [false; 16];
+
+        chip8.handle_next_instruction(&keypad).unwrap();
+        assert_eq!(0x200, chip8.program_counter);
+
+        keypad[0xB] = true;
+        chip8.handle_next_instruction(&keypad).unwrap();
+        assert_eq!(0x200, chip8.program_counter);
+
+        keypad[0xB] = false;
+        chip8.handle_next_instruction(&keypad).unwrap();
+        assert_eq!(0x202, chip8.program_counter);
+        assert_eq!(0xB, chip8.registers[3]);
+    }
+
+    #[test]
+    fn return_with_empty_stack_test() {
+        let mut chip8 = chip8_with_program(&[0x00, 0xEE]);
+
+        let result = chip8.handle_next_instruction(&[false; 16]);
+
+        assert!(matches!(
+            result,
+            Err(Chip8Error::StackUnderflow { pc: 0x200 })
+        ));
+    }
+
+    #[test]
+    fn unknown_opcode_halts_test() {
+        let mut chip8 = chip8_with_program(&[0x81, 0x2F]);
+
+        let result = chip8.handle_next_instruction(&[false; 16]);
+
+        assert!(matches!(
+            result,
+            Err(Chip8Error::InvalidOpcode {
+                pc: 0x200,
+                opcode: 0x812F
+            })
+        ));
+        assert_eq!(0x200, chip8.program_counter);
+
+        // running off the end of a program into zeroed memory stops too
+        let mut chip8 = chip8_with_program(&[0x60, 0x01]);
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        assert!(matches!(
+            chip8.handle_next_instruction(&[false; 16]),
+            Err(Chip8Error::InvalidOpcode {
+                pc: 0x202,
+                opcode: 0x0000
+            })
+        ));
+    }
+
+    #[test]
+    fn end_of_memory_wraps_test() {
+        // 6105 then 3105 at the very end of memory, the skip steps over address 0
+        let mut chip8 = chip8_with_program(&[]);
+        chip8.memory[0xFFFC..].copy_from_slice(&[0x61, 0x05, 0x31, 0x05]);
+        chip8.program_counter = 0xFFFC;
+
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+
+        assert_eq!(0x0002, chip8.program_counter);
+    }
+
+    #[test]
+    fn register_dump_past_end_of_memory_test() {
+        // I = 0xFFFE, FX55 with x = 3 would write up to 0x10001
+        let mut chip8 = chip8_with_program(&[0xF0, 0x00, 0xFF, 0xFE, 0xF3, 0x55]);
+
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        let result = chip8.handle_next_instruction(&[false; 16]);
+
+        assert!(matches!(
+            result,
+            Err(Chip8Error::MemoryOutOfBounds { addr: 0x10001 })
+        ));
+    }
+
+    #[test]
+    fn rom_too_large_test() {
+        let mut chip8 = Chip8::default();
+
+        let result = chip8.load_rom_bytes(&[0; 0x10000]);
+
+        assert!(matches!(
+            result,
+            Err(Chip8Error::RomTooLarge {
+                size: 0x10000,
+                max: 0xFE00
+            })
+        ));
+    }
+
+    #[test]
+    fn shift_quirk_test() {
+        // V1 = 0x81, V2 = 0x06, 8126
+        let program = [0x61, 0x81, 0x62, 0x06, 0x81, 0x26];
+
+        let mut vip = chip8_with_quirks(&program, Quirks::COSMAC_VIP);
+        let mut schip = chip8_with_quirks(&program, Quirks::SUPER_CHIP);
+        for _ in 0..3 {
+            vip.handle_next_instruction(&[false; 16]).unwrap();
+            schip.handle_next_instruction(&[false; 16]).unwrap();
         }
+
+        assert_eq!((0x03, 0), (vip.registers[1], vip.registers[0xF]));
+        assert_eq!((0x40, 1), (schip.registers[1], schip.registers[0xF]));
+    }
+
+    #[test]
+    fn jump_quirk_test() {
+        // V0 = 0x10, V3 = 0x20, B300
+        let program = [0x60, 0x10, 0x63, 0x20, 0xB3, 0x00];
+
+        let mut vip = chip8_with_quirks(&program, Quirks::COSMAC_VIP);
+        let mut chip48 = chip8_with_quirks(&program, Quirks::CHIP_48);
+        for _ in 0..3 {
+            vip.handle_next_instruction(&[false; 16]).unwrap();
+            chip48.handle_next_instruction(&[false; 16]).unwrap();
+        }
+
+        assert_eq!(0x310, vip.program_counter);
+        assert_eq!(0x320, chip48.program_counter);
+    }
+
+    #[test]
+    fn load_store_index_quirk_test() {
+        // I = 0x300, F255
+        let program = [0xA3, 0x00, 0xF2, 0x55];
+
+        let mut vip = chip8_with_quirks(&program, Quirks::COSMAC_VIP);
+        let mut chip48 = chip8_with_quirks(&program, Quirks::CHIP_48);
+        let mut schip = chip8_with_quirks(&program, Quirks::SUPER_CHIP);
+        for chip8 in [&mut vip, &mut chip48, &mut schip] {
+            chip8.handle_next_instruction(&[false; 16]).unwrap();
+            chip8.handle_next_instruction(&[false; 16]).unwrap();
+        }
+
+        assert_eq!(0x303, vip.index_register);
+        assert_eq!(0x302, chip48.index_register);
+        assert_eq!(0x300, schip.index_register);
+    }
+
+    #[test]
+    fn sprite_clip_quirk_test() {
+        // V0 = 62, I = font "0", D011 draws the top row 0xF0 over the right edge
+        let program = [0x60, 0x3E, 0xA0, 0x00, 0xD0, 0x11];
+
+        let mut clipped = chip8_with_quirks(&program, Quirks::SUPER_CHIP);
+        let mut wrapped = chip8_with_quirks(&program, Quirks::XO_CHIP);
+        for chip8 in [&mut clipped, &mut wrapped] {
+            for _ in 0..3 {
+                chip8.handle_next_instruction(&[false; 16]).unwrap();
+            }
+        }
+
+        assert!(clipped.vram[0].get(63, 0));
+        assert!(!clipped.vram[0].get(0, 0));
+        assert!(wrapped.vram[0].get(0, 0) && wrapped.vram[0].get(1, 0));
+    }
+
+    #[test]
+    fn display_wait_quirk_test() {
+        // D001 followed by 6105
+        let mut chip8 = chip8_with_quirks(&[0xD0, 0x01, 0x61, 0x05], Quirks::COSMAC_VIP);
+
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        assert_eq!(0, chip8.registers[1]);
+
+        chip8.vblank();
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        assert_eq!(5, chip8.registers[1]);
+    }
+
+    #[test]
+    fn mid_redraw_test() {
+        // D015 draws font "0", erases it with the same D015 and draws it again
+        let mut chip8 = chip8_with_quirks(&[0xD0, 0x15, 0xD0, 0x15, 0xD0, 0x15], Quirks::CHIP_48);
+
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        assert!(!chip8.is_mid_redraw());
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        assert!(chip8.is_mid_redraw());
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        assert!(!chip8.is_mid_redraw());
+    }
+
+    #[test]
+    fn hires_sprite_test() {
+        // 00FF, V0 = 120, V1 = 60, I = 0x300, D010 with a solid 16x16 sprite
+        let mut chip8 = chip8_with_quirks(
+            &[0x00, 0xFF, 0x60, 0x78, 0x61, 0x3C, 0xA3, 0x00, 0xD0, 0x10],
+            Quirks::SUPER_CHIP,
+        );
+        chip8.memory[0x300..0x320].copy_from_slice(&[0xFF; 32]);
+
+        for _ in 0..5 {
+            chip8.handle_next_instruction(&[false; 16]).unwrap();
+        }
+
+        assert_eq!((128, 64), (chip8.vram[0].width(), chip8.vram[0].height()));
+        assert!(chip8.vram[0].get(120, 60) && chip8.vram[0].get(127, 63));
+        assert!(!chip8.vram[0].get(120, 59) && !chip8.vram[0].get(119, 60));
+    }
+
+    #[test]
+    fn scroll_test() {
+        // D011 draws the top row of font "0" at (0, 0), then 00C2 and 00FB
+        let mut chip8 =
+            chip8_with_quirks(&[0xD0, 0x11, 0x00, 0xC2, 0x00, 0xFB], Quirks::SUPER_CHIP);
+
+        for _ in 0..3 {
+            chip8.handle_next_instruction(&[false; 16]).unwrap();
+        }
+
+        assert!(!chip8.vram[0].get(0, 0) && !chip8.vram[0].get(3, 2));
+        assert!(chip8.vram[0].get(4, 2) && chip8.vram[0].get(7, 2));
+        assert!(!chip8.vram[0].get(8, 2));
+    }
+
+    #[test]
+    fn big_font_and_rpl_flags_test() {
+        // V0 = 2, F030, V1 = 7, F175, V0 = 0, V1 = 0, F185
+        let mut chip8 = chip8_with_quirks(
+            &[
+                0x60, 0x02, 0xF0, 0x30, 0x61, 0x07, 0xF1, 0x75, 0x60, 0x00, 0x61, 0x00, 0xF1, 0x85,
+            ],
+            Quirks::SUPER_CHIP,
+        );
+
+        for _ in 0..7 {
+            chip8.handle_next_instruction(&[false; 16]).unwrap();
+        }
+
+        assert_eq!((BIG_FONT_START + 20) as u16, chip8.index_register);
+        assert_eq!([2, 7], chip8.registers[..2]);
+    }
+
+    #[test]
+    fn exit_test() {
+        let mut chip8 = chip8_with_quirks(&[0x00, 0xFD, 0x60, 0x01], Quirks::SUPER_CHIP);
+
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+
+        assert!(chip8.is_halted());
+        assert_eq!(0, chip8.registers[0]);
+    }
+
+    #[test]
+    fn long_index_load_test() {
+        // V0 = 0, 3000 skips the whole F000 2468, then F000 1234
+        let mut chip8 = chip8_with_quirks(
+            &[0x30, 0x00, 0xF0, 0x00, 0x24, 0x68, 0xF0, 0x00, 0x12, 0x34],
+            Quirks::XO_CHIP,
+        );
+
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        assert_eq!(0x206, chip8.program_counter);
+
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        assert_eq!(0x1234, chip8.index_register);
+        assert_eq!(0x20A, chip8.program_counter);
+    }
+
+    #[test]
+    fn register_range_save_load_test() {
+        // V1..V3 = 1, 2, 3, I = 0x300, 5312 saves them in reverse, 5573 loads them into V5..V7
+        let mut chip8 = chip8_with_quirks(
+            &[
+                0x61, 0x01, 0x62, 0x02, 0x63, 0x03, 0xA3, 0x00, 0x53, 0x12, 0x55, 0x73,
+            ],
+            Quirks::XO_CHIP,
+        );
+
+        for _ in 0..6 {
+            chip8.handle_next_instruction(&[false; 16]).unwrap();
+        }
+
+        assert_eq!([3, 2, 1], chip8.memory[0x300..0x303]);
+        assert_eq!([3, 2, 1], chip8.registers[5..8]);
+        assert_eq!(0x300, chip8.index_register);
+    }
+
+    #[test]
+    fn bitplane_draw_test() {
+        // F201 selects plane 1, D011 draws the top row of font "0" there,
+        // F301 selects both planes and D011 reads two 1 row sprites from I
+        let mut chip8 = chip8_with_quirks(
+            &[0xF2, 0x01, 0xD0, 0x11, 0xF3, 0x01, 0xD0, 0x11],
+            Quirks::XO_CHIP,
+        );
+
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        assert_eq!(0b10, chip8.pixel_color(0, 0));
+        assert_eq!(0, chip8.registers[0xF]);
+
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        chip8.handle_next_instruction(&[false; 16]).unwrap();
+        // plane 0 gets 0xF0, plane 1 gets 0x90 and collides
+        assert_eq!(0b01, chip8.pixel_color(0, 0));
+        assert_eq!(0b11, chip8.pixel_color(1, 0));
+        assert_eq!(1, chip8.registers[0xF]);
+    }
+
+    #[test]
+    fn audio_pattern_and_pitch_test() {
+        // I = 0x300, F002, V0 = 112, F03A
+        let mut chip8 = chip8_with_quirks(
+            &[0xA3, 0x00, 0xF0, 0x02, 0x60, 0x70, 0xF0, 0x3A],
+            Quirks::XO_CHIP,
+        );
+        chip8.memory[0x300..0x310].copy_from_slice(&[0xAA; 16]);
+        assert_eq!(None, chip8.audio_pattern());
+        assert_eq!(4000.0, chip8.audio_pattern_rate());
+
+        for _ in 0..4 {
+            chip8.handle_next_instruction(&[false; 16]).unwrap();
+        }
+
+        assert_eq!(Some([0xAA; 16]), chip8.audio_pattern());
+        // 48 steps above the default doubles the rate
+        assert_eq!(8000.0, chip8.audio_pattern_rate());
+    }
+
+    #[test]
+    fn end_frame_test() {
+        // V0 = 5, FX15 and FX18 set both timers from it
+        let mut chip8 = chip8_with_quirks(&[0x60, 0x05, 0xF0, 0x15, 0xF0, 0x18], Quirks::default());
+        for _ in 0..3 {
+            chip8.handle_next_instruction(&[false; 16]).unwrap();
+        }
+        chip8.awaiting_vblank = true;
+
+        chip8.end_frame();
+        chip8.end_frame();
+
+        assert_eq!((3, 3), (chip8.get_delay_timer(), chip8.get_sound_timer()));
+        assert!(!chip8.awaiting_vblank);
+    }
+
+    #[test]
+    fn save_and_load_state_test() {
+        // 00FF, V0 = 0x2A, 2208 calls a subroutine that draws font "0" with DXY5
+        let program = [0x00, 0xFF, 0x60, 0x2A, 0x22, 0x08, 0x00, 0x00, 0xD1, 0x15];
+        let mut chip8 = chip8_with_quirks(&program, Quirks::SUPER_CHIP);
+        for _ in 0..4 {
+            chip8.handle_next_instruction(&[false; 16]).unwrap();
+        }
+
+        let state = chip8.save_state();
+        let mut restored = Chip8::default();
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(state, restored.save_state());
+        assert_eq!(Quirks::SUPER_CHIP, restored.quirks());
+        assert_eq!(vec![0x204], restored.stack);
+        assert_eq!(0x2A, restored.registers[0]);
+        assert_eq!(
+            (128, 64),
+            (restored.display_width(), restored.display_height())
+        );
+        assert!(restored.vram[0].get(0, 0) && restored.vram[0].get(3, 4));
+    }
+
+    #[test]
+    fn seeded_random_test() {
+        // C0FF twice
+        let program = [0xC0, 0xFF, 0xC1, 0xFF];
+        let mut first = chip8_with_quirks(&program, Quirks::default());
+        let mut second = chip8_with_quirks(&program, Quirks::default());
+        first.seed_random(42);
+        second.seed_random(42);
+
+        first.handle_next_instruction(&[false; 16]).unwrap();
+        second.load_state(&first.save_state()).unwrap();
+        first.handle_next_instruction(&[false; 16]).unwrap();
+        second.handle_next_instruction(&[false; 16]).unwrap();
+
+        let mut expected = SplitMix64::new(42);
+        assert_eq!(expected.next_byte(), first.registers[0]);
+        assert_eq!(expected.next_byte(), first.registers[1]);
+        assert_eq!(first.registers[1], second.registers[1]);
+    }
+
+    #[test]
+    fn load_truncated_state_test() {
+        let mut chip8 = Chip8::default();
+        chip8.set_register_value(3, 7);
+        let state = Chip8::new(Quirks::XO_CHIP).save_state();
+
+        let result = chip8.load_state(&state[..state.len() - 1]);
+
+        assert!(matches!(result, Err(Chip8Error::InvalidSaveState(_))));
+        assert_eq!(7, chip8.registers[3]);
+        assert_eq!(Quirks::default(), chip8.quirks());
     }
 }
@@ -0,0 +1,348 @@
+use crate::chip8::Chip8;
+use crate::config::{Config, Setting};
+use crate::crt::CrtFilter;
+use crate::headless::HeadlessRunner;
+use crate::headless::DEFAULT_INSTRUCTIONS_PER_FRAME;
+use crate::movie::Movie;
+use crate::octo;
+use crate::palette::Palette;
+use crate::quirks::Quirks;
+use std::fs;
+use std::path::Path;
+
+pub const DEFAULT_SCALE: u32 = 10;
+const MAX_SCALE: u32 = 40;
+const MAX_INSTRUCTIONS_PER_FRAME: u32 = 100_000;
+// any more and pixels never really go out
+const MAX_PERSISTENCE: u32 = 95;
+
+pub const HELP: &str = "\
+usage: chip8-emulator [options] <rom>
+
+Runs a CHIP-8, SUPER-CHIP or XO-CHIP ROM (.ch8), or Octo source (.8o).
+
+options:
+  -s, --scale <n>        starting window pixels per CHIP-8 pixel, 1 to 40 (default 10)
+      --integer-scale    only scale the display by whole numbers when resizing
+  -i, --ipf <n>          instructions per 60 Hz frame (default 15)
+  -q, --quirks <preset>  vip, chip48, schip or xochip (default vip)
+  -p, --palette <name>   classic, amber, green, octo or lcd, or rrggbb colours:
+                         background,foreground or all four XO-CHIP colours
+      --persistence <n>  percent of a pixel's light left a frame after it goes off,
+                         0 to 95, fades flicker out like a CRT (default 0, off)
+      --vblank-draw      don't show frames that end between erasing and redrawing
+      --crt <filters>    on, off, or scanlines, grid, glow and curvature strengths
+                         as percentages, e.g. scanlines=60,glow=40 (F7 toggles)
+      --seed <n>         seed for CXNN's random numbers, so runs repeat exactly
+  -m, --mute             no sound
+      --paused           start paused in the debugger
+  -f, --fullscreen       start fullscreen
+      --headless <n>     run n frames without a window and print the final screen
+  -c, --config <file>    settings file, see the README (default chip8.conf)
+      --record <file>    record the keypad into a movie file for exact replays
+      --replay <file>    play a movie back, using its seed, quirks and ipf
+  -h, --help             show this help";
+
+/// What the emulator was asked to do on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run(Options),
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub rom: String,
+    pub scale: u32,
+    pub instructions_per_frame: u32,
+    pub quirks: Quirks,
+    // None leaves it to the config file
+    pub palette: Option<Palette>,
+    // percent, 0 is off
+    pub persistence: u32,
+    pub vblank_draw: bool,
+    // None leaves it to the config file
+    pub crt: Option<CrtFilter>,
+    pub seed: Option<u64>,
+    pub mute: bool,
+    pub start_paused: bool,
+    pub fullscreen: bool,
+    pub integer_scale: bool,
+    pub headless_frames: Option<u64>,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    pub config: Option<String>,
+}
+
+impl Command {
+    /// Parses the arguments after the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+        let mut rom = None;
+        let mut options = Options {
+            rom: String::new(),
+            scale: DEFAULT_SCALE,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            quirks: Quirks::default(),
+            palette: None,
+            persistence: 0,
+            vblank_draw: false,
+            crt: None,
+            seed: None,
+            mute: false,
+            start_paused: false,
+            fullscreen: false,
+            integer_scale: false,
+            headless_frames: None,
+            record: None,
+            replay: None,
+            config: None,
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("{} needs a value, see --help", arg))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help),
+                "-s" | "--scale" => options.scale = parse_number(&arg, &value()?, 1, MAX_SCALE)?,
+                "-i" | "--ipf" => {
+                    options.instructions_per_frame =
+                        parse_number(&arg, &value()?, 1, MAX_INSTRUCTIONS_PER_FRAME)?
+                }
+                "-q" | "--quirks" => options.quirks = parse_quirks(&value()?)?,
+                "-p" | "--palette" => options.palette = Some(Palette::parse(&value()?)?),
+                "--persistence" => {
+                    options.persistence = parse_number(&arg, &value()?, 0, MAX_PERSISTENCE)?
+                }
+                "--vblank-draw" => options.vblank_draw = true,
+                "--crt" => options.crt = Some(CrtFilter::parse(&value()?)?),
+                "--seed" => options.seed = Some(parse_number(&arg, &value()?, 0, u64::MAX)?),
+                "-m" | "--mute" => options.mute = true,
+                "--paused" => options.start_paused = true,
+                "-f" | "--fullscreen" => options.fullscreen = true,
+                "--integer-scale" => options.integer_scale = true,
+                "--headless" => {
+                    options.headless_frames = Some(parse_number(&arg, &value()?, 1, u64::MAX)?)
+                }
+                "--record" => options.record = Some(value()?),
+                "--replay" => options.replay = Some(value()?),
+                "-c" | "--config" => options.config = Some(value()?),
+                _ if arg.starts_with('-') => {
+                    return Err(format!("unknown option '{}', see --help", arg))
+                }
+                _ if rom.is_none() => rom = Some(arg),
+                _ => {
+                    return Err(format!(
+                        "only one ROM can be run at a time, got '{}' too",
+                        arg
+                    ))
+                }
+            }
+        }
+
+        if options.record.is_some() && options.replay.is_some() {
+            return Err(String::from("--record and --replay can't be used together"));
+        }
+        if options.record.is_some() && options.headless_frames.is_some() {
+            return Err(String::from(
+                "headless runs can't be recorded, they have no input",
+            ));
+        }
+
+        options.rom = rom.ok_or("no ROM given, see --help")?;
+        Ok(Command::Run(options))
+    }
+}
+
+fn parse_number<T>(option: &str, text: &str, min: T, max: T) -> Result<T, String>
+where
+    T: std::str::FromStr + PartialOrd + std::fmt::Display,
+{
+    match text.parse::<T>() {
+        Ok(number) if number >= min && number <= max => Ok(number),
+        _ => Err(format!(
+            "{} has to be a number from {} to {}, got '{}'",
+            option, min, max, text
+        )),
+    }
+}
+
+fn parse_quirks(name: &str) -> Result<Quirks, String> {
+    match name.to_ascii_lowercase().as_str() {
+        "vip" | "cosmac-vip" | "chip8" => Ok(Quirks::COSMAC_VIP),
+        "chip48" | "chip-48" => Ok(Quirks::CHIP_48),
+        "schip" | "super-chip" | "superchip" => Ok(Quirks::SUPER_CHIP),
+        "xochip" | "xo-chip" => Ok(Quirks::XO_CHIP),
+        _ => Err(format!(
+            "unknown quirks preset '{}', use vip, chip48, schip or xochip",
+            name
+        )),
+    }
+}
+
+/// The ROM's file name, which is what config file sections are headed with.
+pub fn rom_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map_or(path.to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+/// Fills in the `seed` from the config file unless `--seed` gave one.
+pub fn configure(
+    options: &mut Options,
+    config: &Config,
+    settings: &[&Setting],
+) -> Result<(), String> {
+    let mut seed = None;
+    for setting in settings.iter().filter(|setting| setting.name == "seed") {
+        seed = Some(
+            parse_number("seed", &setting.value, 0, u64::MAX)
+                .map_err(|e| config.error(setting.line, &e))?,
+        );
+    }
+    options.seed = options.seed.or(seed);
+
+    Ok(())
+}
+
+/// A machine with the options' quirks and seed, ready for a ROM.
+pub fn build_chip8(options: &Options) -> Chip8 {
+    let mut chip8 = Chip8::new(options.quirks);
+    if let Some(seed) = options.seed {
+        chip8.seed_random(seed);
+    }
+    chip8
+}
+
+/// Loads the `--replay` movie, if any, and switches the options over to the
+/// seed, quirks and speed it was recorded with.
+pub fn read_replay(options: &mut Options) -> Result<Option<Movie>, String> {
+    let Some(path) = &options.replay else {
+        return Ok(None);
+    };
+    if options.record.is_some() {
+        return Err(String::from("--record and --replay can't be used together"));
+    }
+
+    let text = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    let movie = Movie::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+    options.seed = Some(movie.seed);
+    options.quirks = movie.quirks;
+    options.instructions_per_frame = movie.instructions_per_frame;
+
+    Ok(Some(movie))
+}
+
+/// Runs the ROM for `frames` frames without a window, replaying `--replay` if
+/// given, and returns the final screen and registers as text.
+pub fn run_headless(options: &Options, frames: u64) -> Result<String, String> {
+    let mut options = options.clone();
+    let config = Config::load(options.config.as_deref())?;
+    let settings = config.settings(&rom_name(&options.rom));
+    configure(&mut options, &config, &settings)?;
+    let replay = read_replay(&mut options)?;
+    let program = read_program(&options.rom)?;
+    let mut chip8 = build_chip8(&options);
+    chip8.load_rom_bytes(&program)?;
+
+    let mut runner = HeadlessRunner::new(chip8, options.instructions_per_frame);
+    if let Some(movie) = replay {
+        movie.check_rom(&program)?;
+        runner.set_input_script(movie.key_events());
+    }
+    Ok(runner.run_frames(frames)?.to_string())
+}
+
+/// Reads a ROM image, or compiles it first if it's Octo source.
+pub fn read_program(path: &str) -> Result<Vec<u8>, String> {
+    if path.ends_with(".8o") {
+        let source =
+            fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+        return octo::compile(&source).map_err(|e| format!("{}: {}", path, e));
+    }
+
+    fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        Command::parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parse_options_test() {
+        let Ok(Command::Run(options)) = parse(
+            "--scale 5 -i 30 --quirks schip --seed 7 --mute --paused --persistence 60 game.ch8",
+        ) else {
+            panic!("expected options");
+        };
+
+        assert_eq!("game.ch8", options.rom);
+        assert_eq!(5, options.scale);
+        assert_eq!(30, options.instructions_per_frame);
+        assert_eq!(Quirks::SUPER_CHIP, options.quirks);
+        assert_eq!(Some(7), options.seed);
+        assert_eq!(60, options.persistence);
+        assert!(!options.vblank_draw);
+        assert!(options.mute && options.start_paused && !options.fullscreen);
+        assert_eq!(Ok(Command::Help), parse("game.ch8 --help"));
+    }
+
+    #[test]
+    fn configure_seed_test() {
+        let config = Config::parse("chip8.conf", "seed = 42\n[Game.ch8]\nseed = 7").unwrap();
+        let configured = |args: &str| {
+            let Ok(Command::Run(mut options)) = parse(args) else {
+                panic!("expected options");
+            };
+            let settings = config.settings(&rom_name(&options.rom));
+            configure(&mut options, &config, &settings).unwrap();
+            options.seed
+        };
+
+        assert_eq!(Some(42), configured("roms/Other.ch8"));
+        assert_eq!(Some(7), configured("roms/Game.ch8"));
+        assert_eq!(Some(1), configured("--seed 1 roms/Game.ch8"));
+
+        let config = Config::parse("chip8.conf", "seed = lucky").unwrap();
+        let Ok(Command::Run(mut options)) = parse("Game.ch8") else {
+            panic!("expected options");
+        };
+        assert_eq!(
+            Err(String::from(
+                "chip8.conf:1: seed has to be a number from 0 to 18446744073709551615, got 'lucky'"
+            )),
+            configure(&mut options, &config, &config.settings("Game.ch8"))
+        );
+    }
+
+    #[test]
+    fn invalid_options_test() {
+        assert_eq!(
+            Err(String::from(
+                "--scale has to be a number from 1 to 40, got '0'"
+            )),
+            parse("--scale 0 game.ch8")
+        );
+        assert!(parse("--scale").is_err());
+        assert!(parse("--quirks amiga game.ch8").is_err());
+        assert!(parse("--crt blur=10 game.ch8").is_err());
+        assert!(parse("--turbo game.ch8").is_err());
+        assert!(parse("a.ch8 b.ch8").is_err());
+        assert!(parse("--mute").is_err());
+        assert!(parse("--record a.movie --replay b.movie game.ch8").is_err());
+
+        // library callers building Options by hand are stopped too
+        let Ok(Command::Run(mut options)) = parse("--replay b.movie game.ch8") else {
+            panic!("expected options");
+        };
+        options.record = Some(String::from("a.movie"));
+        assert!(read_replay(&mut options).is_err());
+    }
+}
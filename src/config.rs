@@ -0,0 +1,175 @@
+use std::fs;
+use std::io;
+
+/// Read from the working directory unless `--config` points somewhere else.
+pub const DEFAULT_CONFIG_PATH: &str = "chip8.conf";
+
+/// One `name = value` line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub line: usize,
+    pub name: String,
+    pub value: String,
+}
+
+// settings before the first [rom] header apply to every ROM
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Section {
+    rom: Option<String>,
+    settings: Vec<Setting>,
+}
+
+/// The emulator's config file: `name = value` lines, `#` comments, and
+/// `[Game.ch8]` headers that start settings only used for that ROM. A comment
+/// after a setting needs spaces around its `#`, so `#rrggbb` colours aren't cut off.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    path: String,
+    sections: Vec<Section>,
+}
+
+impl Config {
+    pub fn parse(path: &str, text: &str) -> Result<Config, String> {
+        let mut config = Config {
+            path: path.to_string(),
+            sections: vec![Section::default()],
+        };
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = Config::strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(rom) = line
+                .strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+            {
+                config.sections.push(Section {
+                    rom: Some(rom.trim().to_string()),
+                    settings: Vec::new(),
+                });
+                continue;
+            }
+
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(config.error(line_number, "expected 'name = value'"));
+            };
+            let name = name.trim().to_ascii_lowercase();
+            if !Config::is_known(&name) {
+                return Err(config.error(line_number, &format!("unknown setting '{}'", name)));
+            }
+
+            let setting = Setting {
+                line: line_number,
+                name,
+                value: value.trim().to_string(),
+            };
+            config.sections.last_mut().unwrap().settings.push(setting);
+        }
+
+        Ok(config)
+    }
+
+    /// Reads `path`. A missing file is only an error if it was asked for by name.
+    pub fn load(path: Option<&str>) -> Result<Config, String> {
+        let file = path.unwrap_or(DEFAULT_CONFIG_PATH);
+        match fs::read_to_string(file) {
+            Ok(text) => Config::parse(file, &text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && path.is_none() => {
+                Ok(Config::default())
+            }
+            Err(e) => Err(format!("could not read {}: {}", file, e)),
+        }
+    }
+
+    /// Everything that applies to `rom_name`, global settings first so ROM
+    /// specific ones are seen last and win.
+    pub fn settings(&self, rom_name: &str) -> Vec<&Setting> {
+        self.sections
+            .iter()
+            .filter(|section| section.rom.as_deref().is_none_or(|rom| rom == rom_name))
+            .flat_map(|section| &section.settings)
+            .collect()
+    }
+
+    /// An error message pointing at a setting's line.
+    pub fn error(&self, line: usize, message: &str) -> String {
+        format!("{}:{}: {}", self.path, line, message)
+    }
+
+    // a whole line comment, or ` # ` after a setting
+    fn strip_comment(line: &str) -> &str {
+        if line.trim_start().starts_with('#') {
+            return "";
+        }
+
+        let comment = line.char_indices().find(|&(index, c)| {
+            c == '#'
+                && line[..index].ends_with(char::is_whitespace)
+                && line[index + 1..]
+                    .chars()
+                    .next()
+                    .is_none_or(char::is_whitespace)
+        });
+        match comment {
+            Some((index, _)) => &line[..index],
+            None => line,
+        }
+    }
+
+    fn is_known(name: &str) -> bool {
+        let key = name
+            .strip_prefix("key.")
+            .or_else(|| name.strip_prefix("button."));
+        match key {
+            Some(key) => key.len() == 1 && u8::from_str_radix(key, 16).is_ok(),
+            None => matches!(name, "keys" | "palette" | "crt" | "seed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::palette::Palette;
+
+    #[test]
+    fn rom_sections_test() {
+        let text =
+            "keys = cosmac # the default\n\n[Brick.ch8]\nkey.4 = Left\n[Pong.ch8]\nkeys = hex\n";
+        let config = Config::parse("chip8.conf", text).unwrap();
+
+        let names = |rom| {
+            config
+                .settings(rom)
+                .iter()
+                .map(|setting| format!("{}={}", setting.name, setting.value))
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(vec!["keys=cosmac", "key.4=Left"], names("Brick.ch8"));
+        assert_eq!(vec!["keys=cosmac"], names("Tetris.ch8"));
+    }
+
+    #[test]
+    fn comment_test() {
+        let text = "# colours\n[Brick.ch8]\npalette = #1a1c2c, #f4f4f4 # dark\n";
+        let config = Config::parse("chip8.conf", text).unwrap();
+        let settings = config.settings("Brick.ch8");
+        assert_eq!("#1a1c2c, #f4f4f4", settings[0].value);
+        assert!(Palette::parse(&settings[0].value).is_ok());
+    }
+
+    #[test]
+    fn invalid_config_test() {
+        assert_eq!(
+            Err(String::from("chip8.conf:2: unknown setting 'key.10'")),
+            Config::parse("chip8.conf", "keys = hex\nkey.10 = Q")
+        );
+        assert!(Config::parse("chip8.conf", "keys hex").is_err());
+        assert!(Config::parse("chip8.conf", "button.a = start").is_ok());
+        assert!(Config::parse("chip8.conf", "button.G = start").is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config::{Config, Setting};
+use std::f32::consts::PI;
+
+/// The size filtered frames come out at, whatever the resolution: 8x8 texels per
+/// low-res pixel and 4x4 per hi-res one, enough for scanlines and a grid to show.
+pub const OUTPUT_WIDTH: u32 = 512;
+pub const OUTPUT_HEIGHT: u32 = 256;
+
+// how far the corners bend in at full curvature, in half screens
+const MAX_BEND: f32 = 0.25;
+
+/// A CRT look done on the CPU: the frame is blown up to `OUTPUT_WIDTH` by
+/// `OUTPUT_HEIGHT` with dark gaps between scanlines, a grid between pixels, a glow
+/// bleeding out of lit pixels and the picture bulging like a tube's glass.
+///
+/// Each filter's strength goes from 0 (off) to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CrtFilter {
+    pub enabled: bool,
+    pub scanlines: f32,
+    pub grid: f32,
+    pub glow: f32,
+    pub curvature: f32,
+}
+
+impl CrtFilter {
+    /// `on` for the default look, `off`, or comma separated `filter=percent` for
+    /// any of scanlines, grid, glow and curvature, the ones left out are off.
+    pub fn parse(text: &str) -> Result<CrtFilter, String> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "on" => {
+                return Ok(CrtFilter {
+                    enabled: true,
+                    ..CrtFilter::default()
+                })
+            }
+            "off" => return Ok(CrtFilter::default()),
+            _ => {}
+        }
+
+        let mut filter = CrtFilter {
+            enabled: true,
+            scanlines: 0.0,
+            grid: 0.0,
+            glow: 0.0,
+            curvature: 0.0,
+        };
+        for part in text.split(',') {
+            let (name, percent) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected 'filter=percent', got '{}'", part.trim()))?;
+            let strength = match percent.trim().parse::<u32>() {
+                Ok(percent) if percent <= 100 => percent as f32 / 100.0,
+                _ => return Err(format!("'{}' is not a percentage", percent.trim())),
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "scanlines" => filter.scanlines = strength,
+                "grid" => filter.grid = strength,
+                "glow" => filter.glow = strength,
+                "curvature" => filter.curvature = strength,
+                name => {
+                    return Err(format!(
+                        "unknown filter '{}', use scanlines, grid, glow or curvature",
+                        name
+                    ))
+                }
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /// Applies `crt = ...` settings, the last one wins.
+    pub fn configure(&mut self, config: &Config, settings: &[&Setting]) -> Result<(), String> {
+        for setting in settings.iter().filter(|setting| setting.name == "crt") {
+            *self = CrtFilter::parse(&setting.value).map_err(|e| config.error(setting.line, &e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Filters an RGB24 frame of `width` by `height` pixels into `output`, which ends
+    /// up `OUTPUT_WIDTH` by `OUTPUT_HEIGHT`.
+    pub fn apply(&self, pixels: &[u8], width: u32, height: u32, output: &mut Vec<u8>) {
+        let glow = CrtFilter::blur(pixels, width, height);
+        let texels_per_pixel = (OUTPUT_WIDTH / width) as f32;
+        let bend = self.curvature * MAX_BEND;
+
+        output.clear();
+        for texel_y in 0..OUTPUT_HEIGHT {
+            for texel_x in 0..OUTPUT_WIDTH {
+                // -1 to 1 across the screen, pushed outwards the further from the middle
+                let u = (texel_x as f32 + 0.5) / OUTPUT_WIDTH as f32 * 2.0 - 1.0;
+                let v = (texel_y as f32 + 0.5) / OUTPUT_HEIGHT as f32 * 2.0 - 1.0;
+                let (u, v) = (u * (1.0 + bend * v * v), v * (1.0 + bend * u * u));
+                if u.abs() > 1.0 || v.abs() > 1.0 {
+                    // bent off the edge of the glass
+                    output.extend_from_slice(&[0; 3]);
+                    continue;
+                }
+
+                // where that lands on the CHIP-8 display, in pixels
+                let x = (u + 1.0) / 2.0 * width as f32;
+                let y = (v + 1.0) / 2.0 * height as f32;
+                let (pixel_x, pixel_y) = ((x as u32).min(width - 1), (y as u32).min(height - 1));
+
+                // brightest in the middle of a scanline, darkest between them
+                let mut brightness = 1.0 - self.scanlines * (1.0 - (y.fract() * PI).sin());
+                if x.fract() * texels_per_pixel < 1.0 || y.fract() * texels_per_pixel < 1.0 {
+                    brightness *= 1.0 - self.grid;
+                }
+
+                let index = (pixel_y * width + pixel_x) as usize * 3;
+                let halo = CrtFilter::sample(&glow, width, height, x - 0.5, y - 0.5);
+                for channel in 0..3 {
+                    let value =
+                        pixels[index + channel] as f32 * brightness + halo[channel] * self.glow;
+                    output.push(value.min(255.0) as u8);
+                }
+            }
+        }
+    }
+
+    // each pixel averaged with its neighbours, what spills out of it
+    fn blur(pixels: &[u8], width: u32, height: u32) -> Vec<[f32; 3]> {
+        let (width, height) = (width as i32, height as i32);
+        let mut blurred = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0.0; 3];
+                for (dx, dy) in (-1..=1).flat_map(|dx| (-1..=1).map(move |dy| (dx, dy))) {
+                    let (x, y) = ((x + dx).clamp(0, width - 1), (y + dy).clamp(0, height - 1));
+                    let index = (y * width + x) as usize * 3;
+                    for channel in 0..3 {
+                        sum[channel] += pixels[index + channel] as f32 / 9.0;
+                    }
+                }
+                blurred.push(sum);
+            }
+        }
+        blurred
+    }
+
+    // bilinear, so the glow is smooth instead of coming in blocks
+    fn sample(image: &[[f32; 3]], width: u32, height: u32, x: f32, y: f32) -> [f32; 3] {
+        let x = x.clamp(0.0, (width - 1) as f32);
+        let y = y.clamp(0.0, (height - 1) as f32);
+        let (left, top) = (x as u32, y as u32);
+        let (right, bottom) = ((left + 1).min(width - 1), (top + 1).min(height - 1));
+        let (across, down) = (x.fract(), y.fract());
+
+        let at = |x: u32, y: u32| image[(y * width + x) as usize];
+        let mut color = [0.0; 3];
+        for (channel, value) in color.iter_mut().enumerate() {
+            let upper = at(left, top)[channel] * (1.0 - across) + at(right, top)[channel] * across;
+            let lower =
+                at(left, bottom)[channel] * (1.0 - across) + at(right, bottom)[channel] * across;
+            *value = upper * (1.0 - down) + lower * down;
+        }
+        color
+    }
+}
+
+impl Default for CrtFilter {
+    // off, but the hotkey turns on a gentle look that still leaves the pixels readable
+    fn default() -> Self {
+        CrtFilter {
+            enabled: false,
+            scanlines: 0.5,
+            grid: 0.0,
+            glow: 0.3,
+            curvature: 0.15,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_crt_test() {
+        assert!(CrtFilter::parse("on").unwrap().enabled);
+        assert_eq!(Ok(CrtFilter::default()), CrtFilter::parse("Off"));
+
+        let filter = CrtFilter::parse("scanlines=60, glow=100").unwrap();
+        assert_eq!(
+            (0.6, 0.0, 1.0),
+            (filter.scanlines, filter.grid, filter.glow)
+        );
+        assert!(filter.enabled);
+
+        assert!(CrtFilter::parse("scanlines=101").is_err());
+        assert!(CrtFilter::parse("blur=10").is_err());
+        assert!(CrtFilter::parse("scanlines").is_err());
+    }
+
+    #[test]
+    fn apply_crt_test() {
+        let white = vec![0xFF; 64 * 32 * 3];
+        let texel = |output: &[u8], x: u32, y: u32| output[((y * OUTPUT_WIDTH + x) * 3) as usize];
+        let mut output = Vec::new();
+
+        let scanlines = CrtFilter::parse("scanlines=100").unwrap();
+        scanlines.apply(&white, 64, 32, &mut output);
+        assert_eq!((OUTPUT_WIDTH * OUTPUT_HEIGHT * 3) as usize, output.len());
+        // the middle of the first row of pixels and the gap under it
+        assert!(texel(&output, 100, 4) > 0xF0);
+        assert!(texel(&output, 100, 0) < 0x40);
+
+        let curved = CrtFilter::parse("curvature=100").unwrap();
+        curved.apply(&white, 64, 32, &mut output);
+        assert_eq!(0, texel(&output, 0, 0));
+        assert_eq!(0xFF, texel(&output, OUTPUT_WIDTH / 2, OUTPUT_HEIGHT / 2));
+    }
+}
@@ -0,0 +1,657 @@
+use crate::chip8::{Chip8, MemoryAccess};
+use crate::error::Chip8Error;
+use crate::instruction::Instruction;
+use crate::keypad::Keypad;
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u16,
+    pub kind: WatchKind,
+}
+
+/// Why execution paused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(u16),
+    Watchpoint { addr: u16, write: bool },
+    RegisterCondition { register: u8, value: u8 },
+    Halted,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Step => write!(f, "step"),
+            StopReason::Breakpoint(addr) => write!(f, "breakpoint {:#05x}", addr),
+            StopReason::Watchpoint { addr, write: true } => {
+                write!(f, "watchpoint {:#05x} written", addr)
+            }
+            StopReason::Watchpoint { addr, write: false } => {
+                write!(f, "watchpoint {:#05x} read", addr)
+            }
+            StopReason::RegisterCondition { register, value } => {
+                write!(f, "V{:X} == {:#04x}", register, value)
+            }
+            StopReason::Halted => write!(f, "program exited"),
+        }
+    }
+}
+
+/// One line typed into the debug console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugCommand {
+    Help,
+    Pause,
+    Continue,
+    Step,
+    StepOver,
+    StepOut,
+    Break(u16),
+    Delete(u16),
+    Watch(Watchpoint),
+    Unwatch(u16),
+    Condition { register: u8, value: u8 },
+    RemoveCondition(u8),
+    Registers,
+    Memory { addr: u16, len: u16 },
+    List,
+}
+
+const HELP: &str = "\
+pause                 pause execution
+c, continue           resume execution
+s, step               run one instruction
+n, next               step, running 2NNN calls through to their return
+f, finish             run until the current subroutine returns
+b, break ADDR         break when PC reaches ADDR
+d, delete ADDR        remove the breakpoint at ADDR
+watch ADDR [r|w|rw]   break when ADDR is read and/or written (default rw)
+unwatch ADDR          remove the watchpoint at ADDR
+cond VX VALUE         break when VX becomes VALUE
+uncond VX             remove the condition on VX
+r, regs               show registers
+x, mem ADDR [LEN]     dump LEN bytes of memory from ADDR (default 16)
+list                  show breakpoints, watchpoints and conditions
+h, help               show this help
+addresses are hex, values are decimal or 0x-prefixed hex";
+
+impl DebugCommand {
+    pub fn parse(line: &str) -> Result<DebugCommand, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let argument = |index: usize| {
+            words
+                .get(index)
+                .copied()
+                .ok_or_else(|| format!("'{}' is missing an argument", words[0]))
+        };
+
+        match words.first().copied() {
+            None => Err(String::from("empty command")),
+            Some("h" | "help") => Ok(DebugCommand::Help),
+            Some("pause") => Ok(DebugCommand::Pause),
+            Some("c" | "continue") => Ok(DebugCommand::Continue),
+            Some("s" | "step") => Ok(DebugCommand::Step),
+            Some("n" | "next") => Ok(DebugCommand::StepOver),
+            Some("f" | "finish") => Ok(DebugCommand::StepOut),
+            Some("b" | "break") => Ok(DebugCommand::Break(parse_address(argument(1)?)?)),
+            Some("d" | "delete") => Ok(DebugCommand::Delete(parse_address(argument(1)?)?)),
+            Some("watch") => {
+                let kind = match words.get(2).copied() {
+                    None | Some("rw") => WatchKind::ReadWrite,
+                    Some("r") => WatchKind::Read,
+                    Some("w") => WatchKind::Write,
+                    Some(other) => return Err(format!("unknown watch kind '{}'", other)),
+                };
+                Ok(DebugCommand::Watch(Watchpoint {
+                    addr: parse_address(argument(1)?)?,
+                    kind,
+                }))
+            }
+            Some("unwatch") => Ok(DebugCommand::Unwatch(parse_address(argument(1)?)?)),
+            Some("cond") => Ok(DebugCommand::Condition {
+                register: parse_register(argument(1)?)?,
+                value: parse_value(argument(2)?)?,
+            }),
+            Some("uncond") => Ok(DebugCommand::RemoveCondition(parse_register(argument(1)?)?)),
+            Some("r" | "regs") => Ok(DebugCommand::Registers),
+            Some("x" | "mem") => Ok(DebugCommand::Memory {
+                addr: parse_address(argument(1)?)?,
+                len: match words.get(2) {
+                    Some(len) => parse_value(len)? as u16,
+                    None => 16,
+                },
+            }),
+            Some("list") => Ok(DebugCommand::List),
+            Some(other) => Err(format!("unknown command '{}', try 'help'", other)),
+        }
+    }
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("'{}' is not a hex address", text))
+}
+
+fn parse_register(text: &str) -> Result<u8, String> {
+    let digit = text.trim_start_matches(['v', 'V']);
+    match u8::from_str_radix(digit, 16) {
+        Ok(register) if digit.len() == 1 => Ok(register),
+        _ => Err(format!("'{}' is not a register, use V0 to VF", text)),
+    }
+}
+
+fn parse_value(text: &str) -> Result<u8, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("'{}' is not a byte value", text))
+}
+
+// where a step over or step out should stop
+#[derive(Debug, Clone, Copy)]
+enum StepTarget {
+    ReturnTo { pc: u16, depth: usize },
+    StackBelow(usize),
+}
+
+/// Pause, step and break support around a `Chip8`. Frontends call
+/// `run_instruction` where they would call `handle_next_instruction`.
+#[derive(Default)]
+pub struct Debugger {
+    paused: bool,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    register_conditions: Vec<(u8, u8)>,
+    step_target: Option<StepTarget>,
+    // execution just resumed, so a breakpoint on the instruction it resumes from has
+    // already been stopped at (or was stepped on to by hand) and doesn't fire again
+    resuming: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.step_target = None;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.resuming = true;
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.remove_watchpoint(watchpoint.addr);
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u16) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.addr != addr);
+        self.watchpoints.len() != before
+    }
+
+    /// Breaks when `register` changes to `value`.
+    pub fn add_register_condition(&mut self, register: u8, value: u8) {
+        self.remove_register_condition(register);
+        self.register_conditions.push((register & 0x0F, value));
+    }
+
+    pub fn remove_register_condition(&mut self, register: u8) -> bool {
+        let before = self.register_conditions.len();
+        self.register_conditions
+            .retain(|&(condition_register, _)| condition_register != register & 0x0F);
+        self.register_conditions.len() != before
+    }
+
+    /// Runs one instruction unless paused. Returns why it stopped if this
+    /// instruction hit a breakpoint, watchpoint, condition or step target.
+    pub fn run_instruction(
+        &mut self,
+        chip8: &mut Chip8,
+        keypad: &impl Keypad,
+    ) -> Result<Option<StopReason>, Chip8Error> {
+        if self.paused {
+            return Ok(None);
+        }
+
+        // catches a breakpoint the machine got to without executing an instruction,
+        // e.g. the first one, or after loading a state
+        let pc = chip8.get_program_counter();
+        if !std::mem::take(&mut self.resuming) && self.breakpoints.contains(&pc) {
+            self.pause();
+            return Ok(Some(StopReason::Breakpoint(pc)));
+        }
+
+        let stop_reason = self.execute(chip8, keypad)?;
+        if stop_reason.is_some() {
+            self.pause();
+        }
+
+        Ok(stop_reason)
+    }
+
+    /// Runs exactly one instruction, paused or not, and leaves execution paused.
+    pub fn step(
+        &mut self,
+        chip8: &mut Chip8,
+        keypad: &impl Keypad,
+    ) -> Result<StopReason, Chip8Error> {
+        let stop_reason = self.execute(chip8, keypad)?.unwrap_or(StopReason::Step);
+        self.pause();
+        Ok(stop_reason)
+    }
+
+    /// Like `step`, but a 2NNN call runs until it returns. Resumes execution
+    /// so the frontend keeps timers and input going while the call runs.
+    pub fn step_over(
+        &mut self,
+        chip8: &mut Chip8,
+        keypad: &impl Keypad,
+    ) -> Result<Option<StopReason>, Chip8Error> {
+        let pc = chip8.get_program_counter();
+        let next = Instruction::decode(&chip8.get_memory()[pc as usize..]);
+        if !matches!(next, Some(Instruction::Call(_))) {
+            return self.step(chip8, keypad).map(Some);
+        }
+
+        self.step_target = Some(StepTarget::ReturnTo {
+            pc: pc.wrapping_add(2),
+            depth: chip8.get_stack().len(),
+        });
+        self.resume();
+        Ok(None)
+    }
+
+    /// Resumes until the current subroutine's 00EE has run.
+    pub fn step_out(&mut self, chip8: &Chip8) -> Result<(), String> {
+        let depth = chip8.get_stack().len();
+        if depth == 0 {
+            return Err(String::from("not inside a subroutine"));
+        }
+
+        self.step_target = Some(StepTarget::StackBelow(depth));
+        self.resume();
+        Ok(())
+    }
+
+    fn execute(
+        &mut self,
+        chip8: &mut Chip8,
+        keypad: &impl Keypad,
+    ) -> Result<Option<StopReason>, Chip8Error> {
+        let registers_before = *chip8.get_registers();
+        chip8.handle_next_instruction(keypad)?;
+
+        if chip8.is_halted() {
+            return Ok(Some(StopReason::Halted));
+        }
+
+        for access in chip8.last_memory_accesses() {
+            let (range, write) = match access {
+                MemoryAccess::Read(range) => (range, false),
+                MemoryAccess::Write(range) => (range, true),
+            };
+            let hit = self.watchpoints.iter().find(|watchpoint| {
+                let kind_matches = match watchpoint.kind {
+                    WatchKind::Read => !write,
+                    WatchKind::Write => write,
+                    WatchKind::ReadWrite => true,
+                };
+                kind_matches && range.contains(&(watchpoint.addr as usize))
+            });
+            if let Some(watchpoint) = hit {
+                return Ok(Some(StopReason::Watchpoint {
+                    addr: watchpoint.addr,
+                    write,
+                }));
+            }
+        }
+
+        let registers = chip8.get_registers();
+        for &(register, value) in &self.register_conditions {
+            let index = register as usize;
+            if registers_before[index] != value && registers[index] == value {
+                return Ok(Some(StopReason::RegisterCondition { register, value }));
+            }
+        }
+
+        let pc = chip8.get_program_counter();
+        let depth = chip8.get_stack().len();
+        let target_reached = match self.step_target {
+            Some(StepTarget::ReturnTo {
+                pc: return_pc,
+                depth: return_depth,
+            }) => pc == return_pc && depth == return_depth,
+            Some(StepTarget::StackBelow(call_depth)) => depth < call_depth,
+            None => false,
+        };
+        if target_reached {
+            return Ok(Some(StopReason::Step));
+        }
+
+        if self.breakpoints.contains(&pc) {
+            return Ok(Some(StopReason::Breakpoint(pc)));
+        }
+
+        Ok(None)
+    }
+
+    /// Carries out a console command and returns what to print.
+    pub fn run_command(
+        &mut self,
+        command: DebugCommand,
+        chip8: &mut Chip8,
+        keypad: &impl Keypad,
+    ) -> Result<String, Chip8Error> {
+        let output = match command {
+            DebugCommand::Help => String::from(HELP),
+            DebugCommand::Pause => {
+                self.pause();
+                Debugger::describe(chip8)
+            }
+            DebugCommand::Continue => {
+                self.resume();
+                String::from("continuing")
+            }
+            DebugCommand::Step => {
+                let stop_reason = self.step(chip8, keypad)?;
+                Debugger::describe_stop(stop_reason, chip8)
+            }
+            DebugCommand::StepOver => match self.step_over(chip8, keypad)? {
+                Some(stop_reason) => Debugger::describe_stop(stop_reason, chip8),
+                None => String::from("running to the end of the call"),
+            },
+            DebugCommand::StepOut => match self.step_out(chip8) {
+                Ok(()) => String::from("running to the end of the subroutine"),
+                Err(message) => message,
+            },
+            DebugCommand::Break(addr) => {
+                self.add_breakpoint(addr);
+                format!("breakpoint at {:#05x}", addr)
+            }
+            DebugCommand::Delete(addr) => match self.remove_breakpoint(addr) {
+                true => format!("removed breakpoint at {:#05x}", addr),
+                false => format!("no breakpoint at {:#05x}", addr),
+            },
+            DebugCommand::Watch(watchpoint) => {
+                self.add_watchpoint(watchpoint);
+                format!("watching {:#05x}", watchpoint.addr)
+            }
+            DebugCommand::Unwatch(addr) => match self.remove_watchpoint(addr) {
+                true => format!("removed watchpoint at {:#05x}", addr),
+                false => format!("no watchpoint at {:#05x}", addr),
+            },
+            DebugCommand::Condition { register, value } => {
+                self.add_register_condition(register, value);
+                format!("breaking when V{:X} becomes {:#04x}", register, value)
+            }
+            DebugCommand::RemoveCondition(register) => {
+                match self.remove_register_condition(register) {
+                    true => format!("removed condition on V{:X}", register),
+                    false => format!("no condition on V{:X}", register),
+                }
+            }
+            DebugCommand::Registers => Debugger::describe(chip8),
+            DebugCommand::Memory { addr, len } => Debugger::dump_memory(chip8, addr, len),
+            DebugCommand::List => self.list(),
+        };
+
+        Ok(output)
+    }
+
+    pub fn describe_stop(stop_reason: StopReason, chip8: &Chip8) -> String {
+        format!("stopped: {}\n{}", stop_reason, Debugger::describe(chip8))
+    }
+
+    /// Registers, timers and the next instruction.
+    pub fn describe(chip8: &Chip8) -> String {
+        let pc = chip8.get_program_counter();
+        let memory = chip8.get_memory();
+        let opcode = memory
+            .get(pc as usize..pc as usize + 2)
+            .map(|bytes| format!("{:02X}{:02X}", bytes[0], bytes[1]))
+            .unwrap_or_else(|| String::from("----"));
+        let mnemonic = Instruction::decode(&memory[(pc as usize).min(memory.len())..])
+            .map(|instruction| instruction.to_string())
+            .unwrap_or_else(|| String::from("???"));
+        let registers: Vec<String> = chip8
+            .get_registers()
+            .iter()
+            .enumerate()
+            .map(|(register, value)| format!("V{:X}={:02x}", register, value))
+            .collect();
+
+        format!(
+            "PC={:#05x} [{} {}] I={:#05x} SP={} DT={} ST={}\n{}",
+            pc,
+            opcode,
+            mnemonic,
+            chip8.get_index_register(),
+            chip8.get_stack().len(),
+            chip8.get_delay_timer(),
+            chip8.get_sound_timer(),
+            registers.join(" ")
+        )
+    }
+
+    fn dump_memory(chip8: &Chip8, addr: u16, len: u16) -> String {
+        let memory = chip8.get_memory();
+        let start = addr as usize;
+        let end = (start + len as usize).min(memory.len());
+        if start >= end {
+            return format!("{:#x} is outside memory", addr);
+        }
+
+        memory[start..end]
+            .chunks(16)
+            .enumerate()
+            .map(|(row, bytes)| {
+                let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+                format!("{:#06x}: {}", start + row * 16, hex.join(" "))
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn list(&self) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        lines.extend(
+            self.breakpoints
+                .iter()
+                .map(|addr| format!("break {:#05x}", addr)),
+        );
+        lines.extend(self.watchpoints.iter().map(|watchpoint| {
+            let kind = match watchpoint.kind {
+                WatchKind::Read => "r",
+                WatchKind::Write => "w",
+                WatchKind::ReadWrite => "rw",
+            };
+            format!("watch {:#05x} {}", watchpoint.addr, kind)
+        }));
+        lines.extend(
+            self.register_conditions
+                .iter()
+                .map(|(register, value)| format!("cond V{:X} {:#04x}", register, value)),
+        );
+
+        if lines.is_empty() {
+            String::from("nothing set")
+        } else {
+            lines.join("\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn chip8_with_program(program: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::default();
+        chip8.load_rom_bytes(program).unwrap();
+        chip8
+    }
+
+    // 2206 calls a subroutine, 1204 loops, the subroutine sets V0 = 5 and returns
+    const CALL_PROGRAM: [u8; 10] = [0x22, 0x06, 0x00, 0x00, 0x12, 0x04, 0x60, 0x05, 0x00, 0xEE];
+
+    #[test]
+    fn breakpoint_test() {
+        let mut chip8 = chip8_with_program(&CALL_PROGRAM);
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x208);
+
+        let mut stop_reason = None;
+        for _ in 0..10 {
+            stop_reason =
+                stop_reason.or(debugger.run_instruction(&mut chip8, &[false; 16]).unwrap());
+        }
+
+        assert_eq!(Some(StopReason::Breakpoint(0x208)), stop_reason);
+        assert!(debugger.is_paused());
+        assert_eq!(0x208, chip8.get_program_counter());
+    }
+
+    #[test]
+    fn continue_from_breakpoint_test() {
+        let mut chip8 = chip8_with_program(&CALL_PROGRAM);
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x200);
+        debugger.add_breakpoint(0x206);
+
+        // the very first instruction is stopped at before it runs
+        assert_eq!(
+            Some(StopReason::Breakpoint(0x200)),
+            debugger.run_instruction(&mut chip8, &[false; 16]).unwrap()
+        );
+        assert_eq!(0x200, chip8.get_program_counter());
+
+        // continuing runs it instead of stopping again, then stops at the next one
+        debugger.resume();
+        assert_eq!(
+            Some(StopReason::Breakpoint(0x206)),
+            debugger.run_instruction(&mut chip8, &[false; 16]).unwrap()
+        );
+        debugger.resume();
+        assert_eq!(
+            None,
+            debugger.run_instruction(&mut chip8, &[false; 16]).unwrap()
+        );
+        assert_eq!(0x208, chip8.get_program_counter());
+    }
+
+    #[test]
+    fn step_over_and_out_test() {
+        let mut chip8 = chip8_with_program(&CALL_PROGRAM);
+        let mut debugger = Debugger::new();
+        debugger.pause();
+
+        assert_eq!(None, debugger.step_over(&mut chip8, &[false; 16]).unwrap());
+        let mut stop_reason = None;
+        while stop_reason.is_none() {
+            stop_reason = debugger.run_instruction(&mut chip8, &[false; 16]).unwrap();
+        }
+        assert_eq!(Some(StopReason::Step), stop_reason);
+        assert_eq!(0x202, chip8.get_program_counter());
+        assert_eq!(5, chip8.get_registers()[0]);
+
+        let mut chip8 = chip8_with_program(&CALL_PROGRAM);
+        debugger.step(&mut chip8, &[false; 16]).unwrap();
+        debugger.step_out(&chip8).unwrap();
+        let mut stop_reason = None;
+        while stop_reason.is_none() {
+            stop_reason = debugger.run_instruction(&mut chip8, &[false; 16]).unwrap();
+        }
+        assert_eq!(0x202, chip8.get_program_counter());
+        assert!(chip8.get_stack().is_empty());
+    }
+
+    #[test]
+    fn watchpoint_and_condition_test() {
+        // I = 0x300, V1 = 3, F133 writes 0x300..0x303, then V2 = 9
+        let mut chip8 = chip8_with_program(&[0xA3, 0x00, 0x61, 0x03, 0xF1, 0x33, 0x62, 0x09]);
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(Watchpoint {
+            addr: 0x302,
+            kind: WatchKind::Write,
+        });
+        debugger.add_register_condition(2, 9);
+
+        let mut stops = Vec::new();
+        for _ in 0..4 {
+            if let Some(stop_reason) = debugger.run_instruction(&mut chip8, &[false; 16]).unwrap() {
+                stops.push(stop_reason);
+                debugger.resume();
+            }
+        }
+
+        assert_eq!(
+            vec![
+                StopReason::Watchpoint {
+                    addr: 0x302,
+                    write: true
+                },
+                StopReason::RegisterCondition {
+                    register: 2,
+                    value: 9
+                },
+            ],
+            stops
+        );
+
+        // registers are masked the same way going in and out
+        debugger.add_register_condition(0x13, 1);
+        assert!(debugger.remove_register_condition(0x13));
+        assert!(!debugger.remove_register_condition(3));
+    }
+
+    #[test]
+    fn parse_command_test() {
+        assert_eq!(Ok(DebugCommand::Break(0x2A4)), DebugCommand::parse("b 2a4"));
+        assert_eq!(
+            Ok(DebugCommand::Watch(Watchpoint {
+                addr: 0x300,
+                kind: WatchKind::Read
+            })),
+            DebugCommand::parse("watch 0x300 r")
+        );
+        assert_eq!(
+            Ok(DebugCommand::Condition {
+                register: 0xA,
+                value: 0x10
+            }),
+            DebugCommand::parse("cond VA 16")
+        );
+        assert!(DebugCommand::parse("cond V10 1").is_err());
+        assert!(DebugCommand::parse("break").is_err());
+    }
+}
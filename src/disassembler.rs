@@ -0,0 +1,388 @@
+use crate::chip8::PROGRAM_START;
+use crate::instruction::Instruction;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+const DATA_BYTES_PER_LINE: usize = 8;
+// where the opcode comments line up
+const COMMENT_COLUMN: usize = 28;
+
+/// Mnemonic flavour for listings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// Cowgod's technical reference, `LD V0, 0x05`. `assembler` reads this back.
+    Cowgod,
+    /// Octo, `v0 := 0x05`.
+    Octo,
+}
+
+// ordered by how much a label name should say, a called address is a `sub_` even if it's also jumped to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Target {
+    Data,
+    Jump,
+    Call,
+}
+
+enum Line {
+    Code {
+        addr: u16,
+        instruction: Instruction,
+        bytes: Vec<u8>,
+    },
+    Data {
+        addr: u16,
+        bytes: Vec<u8>,
+    },
+}
+
+/// A ROM split into code and data by following every path the program can take from 0x200.
+pub struct Disassembly {
+    lines: Vec<Line>,
+    labels: BTreeMap<u16, String>,
+}
+
+impl Disassembly {
+    pub fn new(rom: &[u8]) -> Disassembly {
+        let (code, targets) = Disassembly::trace(rom);
+
+        let end = PROGRAM_START + rom.len();
+        let mut lines = Vec::new();
+        let mut addr = PROGRAM_START;
+        while addr < end {
+            let offset = addr - PROGRAM_START;
+            let instruction = code
+                .contains(&(addr as u16))
+                .then(|| Instruction::decode(&rom[offset..]))
+                .flatten();
+            if let Some(instruction) = instruction {
+                let size = instruction.size() as usize;
+                lines.push(Line::Code {
+                    addr: addr as u16,
+                    instruction,
+                    bytes: rom[offset..offset + size].to_vec(),
+                });
+                addr += size;
+                continue;
+            }
+
+            // data runs until the next instruction or anything that wants a label
+            let mut data_end = addr + 1;
+            while data_end < end
+                && data_end - addr < DATA_BYTES_PER_LINE
+                && !code.contains(&(data_end as u16))
+                && !targets.contains_key(&(data_end as u16))
+            {
+                data_end += 1;
+            }
+            lines.push(Line::Data {
+                addr: addr as u16,
+                bytes: rom[offset..data_end - PROGRAM_START].to_vec(),
+            });
+            addr = data_end;
+        }
+
+        // only addresses that start a line can be labelled, the rest stay as numbers
+        let line_starts: BTreeSet<u16> = lines.iter().map(Line::addr).collect();
+        let mut labels: BTreeMap<u16, String> = targets
+            .into_iter()
+            .filter(|(addr, _)| line_starts.contains(addr))
+            .map(|(addr, target)| {
+                let prefix = match target {
+                    Target::Call => "sub",
+                    Target::Jump => "label",
+                    Target::Data => "data",
+                };
+                (addr, format!("{}_{:03x}", prefix, addr))
+            })
+            .collect();
+        // Octo programs start at `main`, and it reads better than label_200 either way
+        if !rom.is_empty() {
+            labels.insert(PROGRAM_START as u16, String::from("main"));
+        }
+
+        Disassembly { lines, labels }
+    }
+
+    // recursive descent from the entry point: returns where instructions start,
+    // and every address something jumps to, calls or points I at
+    fn trace(rom: &[u8]) -> (BTreeSet<u16>, BTreeMap<u16, Target>) {
+        let in_rom =
+            |addr: u16| (PROGRAM_START..PROGRAM_START + rom.len()).contains(&(addr as usize));
+        let decode_at = |addr: u16| match in_rom(addr) {
+            true => Instruction::decode(&rom[addr as usize - PROGRAM_START..]),
+            false => None,
+        };
+
+        let mut code = BTreeSet::new();
+        let mut targets: BTreeMap<u16, Target> = BTreeMap::new();
+        let add_target = |targets: &mut BTreeMap<u16, Target>, addr: u16, target: Target| {
+            if in_rom(addr) {
+                let entry = targets.entry(addr).or_insert(target);
+                *entry = (*entry).max(target);
+            }
+        };
+
+        let mut pending = vec![PROGRAM_START as u16];
+        while let Some(addr) = pending.pop() {
+            if code.contains(&addr) {
+                continue;
+            }
+            let Some(instruction) = decode_at(addr) else {
+                continue;
+            };
+            code.insert(addr);
+
+            let next = addr.wrapping_add(instruction.size());
+            match instruction {
+                Instruction::Ret | Instruction::Exit => {}
+                Instruction::Jp(nnn) => {
+                    add_target(&mut targets, nnn, Target::Jump);
+                    pending.push(nnn);
+                }
+                Instruction::Call(nnn) => {
+                    add_target(&mut targets, nnn, Target::Call);
+                    pending.extend([nnn, next]);
+                }
+                // the offset isn't known until runtime, the table usually starts at NNN itself
+                Instruction::JpOffset(nnn) => {
+                    add_target(&mut targets, nnn, Target::Jump);
+                    pending.push(nnn);
+                }
+                Instruction::SeImm { .. }
+                | Instruction::SneImm { .. }
+                | Instruction::SeReg { .. }
+                | Instruction::SneReg { .. }
+                | Instruction::Skp(_)
+                | Instruction::Sknp(_) => {
+                    let skipped = decode_at(next).map_or(2, |skipped| skipped.size());
+                    pending.extend([next, next.wrapping_add(skipped)]);
+                }
+                Instruction::LdI(nnn) | Instruction::LdILong(nnn) => {
+                    add_target(&mut targets, nnn, Target::Data);
+                    pending.push(next);
+                }
+                _ => pending.push(next),
+            }
+        }
+
+        (code, targets)
+    }
+
+    pub fn listing(&self, syntax: Syntax) -> String {
+        let mut listing = String::new();
+        let comment = match syntax {
+            Syntax::Cowgod => ';',
+            Syntax::Octo => '#',
+        };
+
+        for line in &self.lines {
+            let addr = line.addr();
+            match (self.labels.get(&addr), syntax) {
+                (Some(label), Syntax::Cowgod) => writeln!(listing, "{}:", label).unwrap(),
+                (Some(label), Syntax::Octo) => writeln!(listing, ": {}", label).unwrap(),
+                (None, _) => {}
+            }
+
+            let (text, bytes) = match line {
+                Line::Code {
+                    instruction, bytes, ..
+                } => (self.format_instruction(*instruction, syntax), bytes),
+                Line::Data { bytes, .. } => (Disassembly::format_data(bytes, syntax), bytes),
+            };
+            let raw: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            writeln!(
+                listing,
+                "    {:<width$} {} {:#05x}  {}",
+                text,
+                comment,
+                addr,
+                raw.join(""),
+                width = COMMENT_COLUMN - 4
+            )
+            .unwrap();
+        }
+
+        listing
+    }
+
+    fn label(&self, addr: u16) -> String {
+        match self.labels.get(&addr) {
+            Some(label) => label.clone(),
+            None => format!("{:#05x}", addr),
+        }
+    }
+
+    fn format_data(bytes: &[u8], syntax: Syntax) -> String {
+        let values: Vec<String> = bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+        match syntax {
+            Syntax::Cowgod => format!("DB {}", values.join(", ")),
+            Syntax::Octo => values.join(" "),
+        }
+    }
+
+    fn format_instruction(&self, instruction: Instruction, syntax: Syntax) -> String {
+        match syntax {
+            Syntax::Cowgod => match instruction {
+                Instruction::Jp(nnn) => format!("JP {}", self.label(nnn)),
+                Instruction::Call(nnn) => format!("CALL {}", self.label(nnn)),
+                Instruction::LdI(nnn) => format!("LD I, {}", self.label(nnn)),
+                Instruction::JpOffset(nnn) => format!("JP V0, {}", self.label(nnn)),
+                Instruction::LdILong(nnnn) => format!("LD I, LONG {}", self.label(nnnn)),
+                _ => instruction.to_string(),
+            },
+            Syntax::Octo => self.format_octo(instruction),
+        }
+    }
+
+    fn format_octo(&self, instruction: Instruction) -> String {
+        match instruction {
+            // Octo has no SYS, the raw bytes still assemble to it
+            Instruction::Sys(nnn) => format!("{:#04x} {:#04x}", nnn >> 8, nnn & 0xFF),
+            Instruction::Cls => String::from("clear"),
+            Instruction::Ret => String::from("return"),
+            Instruction::ScrollDown(n) => format!("scroll-down {}", n),
+            Instruction::ScrollUp(n) => format!("scroll-up {}", n),
+            Instruction::ScrollRight => String::from("scroll-right"),
+            Instruction::ScrollLeft => String::from("scroll-left"),
+            Instruction::Exit => String::from("exit"),
+            Instruction::LowRes => String::from("lores"),
+            Instruction::HighRes => String::from("hires"),
+            Instruction::Jp(nnn) => format!("jump {}", self.label(nnn)),
+            Instruction::Call(nnn) => format!(":call {}", self.label(nnn)),
+            // Octo spells skips as the condition for running the next instruction
+            Instruction::SeImm { x, nn } => format!("if v{:x} != {:#04x} then", x, nn),
+            Instruction::SneImm { x, nn } => format!("if v{:x} == {:#04x} then", x, nn),
+            Instruction::SeReg { x, y } => format!("if v{:x} != v{:x} then", x, y),
+            Instruction::SaveRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+            Instruction::LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+            Instruction::LdImm { x, nn } => format!("v{:x} := {:#04x}", x, nn),
+            Instruction::AddImm { x, nn } => format!("v{:x} += {:#04x}", x, nn),
+            Instruction::LdReg { x, y } => format!("v{:x} := v{:x}", x, y),
+            Instruction::Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+            Instruction::And { x, y } => format!("v{:x} &= v{:x}", x, y),
+            Instruction::Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+            Instruction::AddReg { x, y } => format!("v{:x} += v{:x}", x, y),
+            Instruction::Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+            Instruction::Shr { x, y } => format!("v{:x} >>= v{:x}", x, y),
+            Instruction::Subn { x, y } => format!("v{:x} =- v{:x}", x, y),
+            Instruction::Shl { x, y } => format!("v{:x} <<= v{:x}", x, y),
+            Instruction::SneReg { x, y } => format!("if v{:x} == v{:x} then", x, y),
+            Instruction::LdI(nnn) => format!("i := {}", self.label(nnn)),
+            Instruction::JpOffset(nnn) => format!("jump0 {}", self.label(nnn)),
+            Instruction::Rnd { x, nn } => format!("v{:x} := random {:#04x}", x, nn),
+            Instruction::Drw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+            Instruction::Skp(x) => format!("if v{:x} -key then", x),
+            Instruction::Sknp(x) => format!("if v{:x} key then", x),
+            Instruction::LdILong(nnnn) => format!("i := long {}", self.label(nnnn)),
+            Instruction::LdAudio => String::from("audio"),
+            Instruction::Plane(n) => format!("plane {}", n),
+            Instruction::LdVxDt(x) => format!("v{:x} := delay", x),
+            Instruction::LdKey(x) => format!("v{:x} := key", x),
+            Instruction::LdDtVx(x) => format!("delay := v{:x}", x),
+            Instruction::LdStVx(x) => format!("buzzer := v{:x}", x),
+            Instruction::AddI(x) => format!("i += v{:x}", x),
+            Instruction::LdFont(x) => format!("i := hex v{:x}", x),
+            Instruction::LdBigFont(x) => format!("i := bighex v{:x}", x),
+            Instruction::Bcd(x) => format!("bcd v{:x}", x),
+            Instruction::LdPitch(x) => format!("pitch := v{:x}", x),
+            Instruction::StoreRegs(x) => format!("save v{:x}", x),
+            Instruction::LoadRegs(x) => format!("load v{:x}", x),
+            Instruction::StoreFlags(x) => format!("saveflags v{:x}", x),
+            Instruction::LoadFlags(x) => format!("loadflags v{:x}", x),
+        }
+    }
+}
+
+impl Line {
+    fn addr(&self) -> u16 {
+        match self {
+            Line::Code { addr, .. } | Line::Data { addr, .. } => *addr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // calls a subroutine that points I at a sprite and draws it, then loops
+    const ROM: [u8; 14] = [
+        0x22, 0x04, // 200: call 204
+        0x12, 0x02, // 202: jump 202
+        0xA2, 0x0A, // 204: I = 20A
+        0xD0, 0x01, // 206: draw
+        0x00, 0xEE, // 208: return
+        0xF0, 0x90, 0x90, 0xF0, // 20A: sprite
+    ];
+
+    #[test]
+    fn code_and_data_are_separated_test() {
+        let disassembly = Disassembly::new(&ROM);
+
+        let kinds: Vec<(u16, bool)> = disassembly
+            .lines
+            .iter()
+            .map(|line| (line.addr(), matches!(line, Line::Code { .. })))
+            .collect();
+        assert_eq!(
+            vec![
+                (0x200, true),
+                (0x202, true),
+                (0x204, true),
+                (0x206, true),
+                (0x208, true),
+                (0x20A, false)
+            ],
+            kinds
+        );
+    }
+
+    #[test]
+    fn cowgod_listing_test() {
+        let listing = Disassembly::new(&ROM).listing(Syntax::Cowgod);
+        let text: Vec<&str> = listing
+            .lines()
+            .map(|line| line.split(';').next().unwrap().trim())
+            .collect();
+
+        assert_eq!(
+            vec![
+                "main:",
+                "CALL sub_204",
+                "label_202:",
+                "JP label_202",
+                "sub_204:",
+                "LD I, data_20a",
+                "DRW V0, V0, 1",
+                "RET",
+                "data_20a:",
+                "DB 0xf0, 0x90, 0x90, 0xf0",
+            ],
+            text
+        );
+        assert!(listing.starts_with("main:\n    CALL sub_204             ; 0x200  2204\n"));
+    }
+
+    #[test]
+    fn octo_listing_test() {
+        let listing = Disassembly::new(&[0x30, 0x01, 0x60, 0x05, 0xF0, 0x0A, 0x12, 0x00])
+            .listing(Syntax::Octo);
+        let text: Vec<&str> = listing
+            .lines()
+            .map(|line| line.split('#').next().unwrap().trim())
+            .collect();
+
+        assert_eq!(
+            vec![
+                ": main",
+                "if v0 != 0x01 then",
+                "v0 := 0x05",
+                "v0 := key",
+                "jump main"
+            ],
+            text
+        );
+    }
+}
@@ -1,33 +1,60 @@
 extern crate sdl2;
+use crate::chip8::{DEFAULT_CHIP8_PIXEL_HEIGHT, DEFAULT_CHIP8_PIXEL_WIDTH};
+use crate::cli::Options;
+use crate::crt::CrtFilter;
+use crate::palette::Palette;
 use crate::renderer::Renderer;
-use sdl2::EventPump;
+use sdl2::video::FullscreenType;
+use sdl2::{EventPump, GameControllerSubsystem};
 
 pub struct DisplayDriver {
     pub renderer: Renderer,
     pub event_pump: EventPump,
+    // None if SDL couldn't start controller support, the keyboard still works
+    pub game_controller: Option<GameControllerSubsystem>,
 }
 
 impl DisplayDriver {
-    pub fn build() -> Result<DisplayDriver, String> {
+    pub fn build(
+        title: &str,
+        options: &Options,
+        palette: Palette,
+        crt: CrtFilter,
+    ) -> Result<DisplayDriver, String> {
         let sdl_context = sdl2::init()?;
         let video_subsystem = sdl_context.video()?;
 
-        let window = video_subsystem
-            .window("rust-sdl2 demo: Video", 640, 320)
-            .position_centered()
-            .opengl()
-            .build()
-            .map_err(|e| e.to_string())?;
+        // --scale only picks the starting size, the window can be resized freely after
+        let width = DEFAULT_CHIP8_PIXEL_WIDTH * options.scale;
+        let height = DEFAULT_CHIP8_PIXEL_HEIGHT * options.scale;
+        let mut window_builder = video_subsystem.window(title, width, height);
+        window_builder.position_centered().resizable().opengl();
+        if options.fullscreen {
+            window_builder.fullscreen_desktop();
+        }
+        let window = window_builder.build().map_err(|e| e.to_string())?;
 
         let event_pump = sdl_context.event_pump()?;
-        let renderer = Renderer::new(window)?;
+        let game_controller = sdl_context
+            .game_controller()
+            .map_err(|e| eprintln!("Controllers are unavailable: {}", e))
+            .ok();
+        let persistence = options.persistence as f32 / 100.0;
+        let renderer = Renderer::new(window, palette, options.integer_scale, persistence, crt)?;
         Ok(DisplayDriver {
-            renderer: renderer,
-            event_pump: event_pump,
+            renderer,
+            event_pump,
+            game_controller,
         })
     }
 
-    pub fn get_window_dimensions(&self) -> (u32, u32) {
-        self.renderer.get_size().unwrap()
+    // between windowed and borderless fullscreen at the desktop's resolution
+    pub fn toggle_fullscreen(&mut self) -> Result<(), String> {
+        let window = self.renderer.window_mut();
+        let fullscreen = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        window.set_fullscreen(fullscreen)
     }
 }
@@ -1,38 +1,146 @@
 use crate::audio::AudioDriver;
 use crate::chip8::Chip8;
+use crate::cli::{self, Options};
+use crate::config::Config;
+use crate::crt::CrtFilter;
+use crate::debugger::{DebugCommand, Debugger};
 use crate::display::DisplayDriver;
-use crate::keyboard::Keyboard;
+use crate::keyboard::{Hotkey, Keyboard};
+use crate::keymap::KeyMap;
+use crate::movie::Movie;
+use crate::palette::Palette;
+use crate::rewind::RewindBuffer;
+use std::fs;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
 use std::time::{Duration, Instant};
 
+// ten seconds of 60 Hz frames
+const REWIND_FRAMES: usize = 600;
+const FRAME_INTERVAL: Duration = Duration::from_nanos(1_000_000_000 / 60);
+const MAX_CATCH_UP_FRAMES: u32 = 4;
+
+// a movie drives the keypad frame by frame, so anything that jumps the
+// machine around (loading states, rewinding) is off while one is going
+enum MovieMode {
+    Off,
+    Recording { path: String, movie: Movie },
+    Replaying { movie: Movie, frame: usize },
+}
+
 pub struct Emulator {
-    audio_driver: AudioDriver,
+    // None when muted or there is no sound device
+    audio_driver: Option<AudioDriver>,
     display_driver: DisplayDriver,
     chip8_processor: Chip8,
     keyboard: Keyboard,
+    rewind_buffer: RewindBuffer,
+    debugger: Debugger,
+    instructions_per_frame: u32,
+    movie_mode: MovieMode,
+    // into Palette::PRESETS, a custom palette starts at the end so the hotkey goes to the first
+    palette_index: usize,
+    vblank_draw: bool,
+    // the last frame ended half way through a redraw and wasn't shown
+    held_frame: bool,
 }
 
 impl Emulator {
-    pub fn build() -> Result<Emulator, String> {
-        let audio_driver = AudioDriver::build();
-        let display_driver = DisplayDriver::build()?;
-        let mut chip8_processor = Chip8::new();
-        let (width, height) = display_driver.get_window_dimensions();
-        chip8_processor.initialize_pixels(height, width)?;
-        let keyboard = Keyboard::new();
+    pub fn build(options: &Options) -> Result<Emulator, String> {
+        let mut options = options.clone();
+        let rom_name = cli::rom_name(&options.rom);
+        let config = Config::load(options.config.as_deref())?;
+        let settings = config.settings(&rom_name);
+        cli::configure(&mut options, &config, &settings)?;
+        let replay = cli::read_replay(&mut options)?;
+        if options.record.is_some() {
+            // a recording is only repeatable if the seed is known
+            options.seed.get_or_insert_with(rand::random);
+        }
+        let options = &options;
+
+        // no sound device only costs the sound, the game still runs
+        let audio_driver = if options.mute {
+            None
+        } else {
+            AudioDriver::build()
+                .map_err(|e| eprintln!("Sound is unavailable: {}", e))
+                .ok()
+        };
+
+        // --palette and --crt beat the config file, which can set them per ROM
+        let mut palette = Palette::default();
+        palette.configure(&config, &settings)?;
+        let palette = options.palette.unwrap_or(palette);
+        let mut crt = CrtFilter::default();
+        crt.configure(&config, &settings)?;
+        let crt = options.crt.unwrap_or(crt);
+        let mut display_driver =
+            DisplayDriver::build(&format!("CHIP-8 - {}", rom_name), options, palette, crt)?;
+
+        let chip8_processor = cli::build_chip8(options);
+        let mut keymap = KeyMap::default();
+        keymap.configure(&config, &settings)?;
+        let keyboard = Keyboard::new(&keymap, display_driver.game_controller.take())?;
+
+        let mut debugger = Debugger::new();
+        if options.start_paused {
+            debugger.pause();
+            println!("Paused, type 'c' to continue or 'help' for debugger commands");
+        }
 
         Ok(Emulator {
             audio_driver,
             display_driver,
             chip8_processor,
             keyboard,
+            rewind_buffer: RewindBuffer::new(REWIND_FRAMES),
+            debugger,
+            instructions_per_frame: options.instructions_per_frame,
+            palette_index: Palette::PRESETS
+                .iter()
+                .position(|&(_, preset)| preset == palette)
+                .unwrap_or(Palette::PRESETS.len() - 1),
+            vblank_draw: options.vblank_draw,
+            held_frame: false,
+            movie_mode: match (&options.record, replay) {
+                (Some(path), _) => MovieMode::Recording {
+                    path: path.clone(),
+                    movie: Movie::new(
+                        &[],
+                        options.seed.unwrap_or_default(),
+                        options.quirks,
+                        options.instructions_per_frame,
+                    ),
+                },
+                (None, Some(movie)) => MovieMode::Replaying { movie, frame: 0 },
+                (None, None) => MovieMode::Off,
+            },
         })
     }
 
     pub fn run(&mut self, rom: String) -> Result<(), String> {
-        self.chip8_processor.load_rom(rom)?;
+        let program = cli::read_program(&rom)?;
+        self.chip8_processor.load_rom_bytes(&program)?;
+        match &mut self.movie_mode {
+            MovieMode::Recording { movie, .. } => movie.rom_checksum = Movie::checksum(&program),
+            MovieMode::Replaying { movie, .. } => movie.check_rom(&program)?,
+            MovieMode::Off => {}
+        }
+
+        // the movie is saved even if the ROM crashed, that's when it's wanted most
+        let result = self.main_loop(&rom);
+        self.save_movie()?;
+        result
+    }
 
-        let interval = Duration::from_nanos(1_000_000_000u64 / 60);
+    fn main_loop(&mut self, rom: &str) -> Result<(), String> {
+        // real time not yet emulated, used up one whole frame at a time so the
+        // number of instructions per frame never depends on how long sleep took
+        let mut lag = Duration::ZERO;
         let mut last_tick = Instant::now();
+        let console = Emulator::spawn_console();
 
         'running: loop {
             if !self
@@ -42,27 +150,243 @@ impl Emulator {
                 break 'running;
             }
 
-            self.chip8_processor
-                .handle_next_instruction(&mut self.keyboard, &mut self.display_driver.event_pump);
-            if self.chip8_processor.vram_changed {
-                self.display_driver.renderer.draw(&mut self.chip8_processor);
+            for hotkey in self.keyboard.take_hotkeys() {
+                self.handle_hotkey(hotkey, rom);
             }
+            if self.keyboard.take_window_changed() {
+                // resized or uncovered, what was on screen may be gone
+                self.chip8_processor.vram_changed = true;
+            }
+
+            while let Ok(line) = console.try_recv() {
+                self.handle_console_line(&line);
+            }
+
             let now = Instant::now();
-            if now.duration_since(last_tick) >= interval {
-                self.chip8_processor.decrement_sound_timer();
-                self.chip8_processor.decrement_delay_timer();
-                last_tick = now;
+            // after a stall (window dragged, machine asleep) drop the backlog instead of fast-forwarding
+            lag = (lag + now.duration_since(last_tick)).min(FRAME_INTERVAL * MAX_CATCH_UP_FRAMES);
+            last_tick = now;
+
+            let mut ran_frame = false;
+            while lag >= FRAME_INTERVAL {
+                lag -= FRAME_INTERVAL;
+                if self.keyboard.is_rewinding() && matches!(self.movie_mode, MovieMode::Off) {
+                    // one recorded frame per frame, so rewinding plays at normal speed
+                    if let Some(state) = self.rewind_buffer.pop() {
+                        self.chip8_processor.load_state(&state)?;
+                        self.held_frame = false;
+                    }
+                } else if !self.debugger.is_paused() {
+                    if !self.run_frame()? {
+                        break 'running;
+                    }
+                    ran_frame = true;
+                }
+            }
+
+            if self.should_draw(ran_frame) {
+                self.display_driver.renderer.draw(&mut self.chip8_processor);
             }
 
-            if self.chip8_processor.get_sound_timer() > 0 {
-                self.audio_driver.play();
-            } else {
-                self.audio_driver.stop();
+            if let Some(audio_driver) = &mut self.audio_driver {
+                audio_driver.set_pattern(
+                    self.chip8_processor.audio_pattern(),
+                    self.chip8_processor.audio_pattern_rate(),
+                );
+                if self.chip8_processor.get_sound_timer() > 0 {
+                    audio_driver.play();
+                } else {
+                    audio_driver.stop();
+                }
             }
 
-            ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 1000));
+            ::std::thread::sleep(FRAME_INTERVAL.saturating_sub(lag));
         }
 
         Ok(())
     }
+
+    // the configured number of instructions then one timer tick, false once the ROM exits.
+    // A breakpoint part way through still ends the frame so timers stay in step with instructions,
+    // movies can't set breakpoints so their frames are always whole
+    fn run_frame(&mut self) -> Result<bool, String> {
+        let keys = self.frame_keys();
+        for _ in 0..self.instructions_per_frame {
+            let stop_reason = self
+                .debugger
+                .run_instruction(&mut self.chip8_processor, &keys)?;
+            if self.chip8_processor.is_halted() {
+                return Ok(false);
+            }
+            if let Some(stop_reason) = stop_reason {
+                println!(
+                    "{}",
+                    Debugger::describe_stop(stop_reason, &self.chip8_processor)
+                );
+                break;
+            }
+        }
+
+        self.chip8_processor.end_frame();
+        self.rewind_buffer.push(self.chip8_processor.save_state());
+        Ok(true)
+    }
+
+    // --vblank-draw holds back a frame that ended half way through redrawing a sprite,
+    // but never two in a row, so a sprite that's erased for good still disappears
+    fn should_draw(&mut self, ran_frame: bool) -> bool {
+        if self.vblank_draw && ran_frame {
+            self.held_frame = self.chip8_processor.is_mid_redraw() && !self.held_frame;
+        }
+        // the debugger shows every step
+        if self.held_frame && !self.debugger.is_paused() {
+            return false;
+        }
+
+        self.chip8_processor.vram_changed || self.display_driver.renderer.is_fading()
+    }
+
+    // the keypad for the next frame: live, recorded on the way through, or from the replay
+    fn frame_keys(&mut self) -> [bool; 16] {
+        let live_keys = *self.keyboard.get_state();
+        match &mut self.movie_mode {
+            MovieMode::Off => live_keys,
+            MovieMode::Recording { movie, .. } => {
+                movie.record_frame(&live_keys);
+                live_keys
+            }
+            MovieMode::Replaying { movie, frame } => {
+                if let Some(keys) = movie.frame_keys(*frame) {
+                    *frame += 1;
+                    return keys;
+                }
+
+                println!(
+                    "Replay finished after {} frames, the keyboard is live again",
+                    frame
+                );
+                self.movie_mode = MovieMode::Off;
+                live_keys
+            }
+        }
+    }
+
+    fn save_movie(&self) -> Result<(), String> {
+        if let MovieMode::Recording { path, movie } = &self.movie_mode {
+            fs::write(path, movie.to_string())
+                .map_err(|e| format!("could not write movie {}: {}", path, e))?;
+            println!("Recorded {} frames to {}", movie.len(), path);
+        }
+
+        Ok(())
+    }
+
+    // stdin is read on its own thread so a blocking read_line never stalls the frame loop
+    fn spawn_console() -> Receiver<String> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        receiver
+    }
+
+    fn handle_console_line(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+
+        match DebugCommand::parse(line) {
+            // steps run instructions outside of a frame and breaking ends a frame early,
+            // neither of which a movie can follow, so breakpoints never get set
+            Ok(
+                DebugCommand::Pause
+                | DebugCommand::Step
+                | DebugCommand::StepOver
+                | DebugCommand::StepOut
+                | DebugCommand::Break(_)
+                | DebugCommand::Watch(_)
+                | DebugCommand::Condition { .. },
+            ) if !matches!(self.movie_mode, MovieMode::Off) => {
+                eprintln!(
+                    "Pausing, stepping and breaking are disabled while a movie is recording or playing"
+                );
+            }
+            // a step that runs into a bad instruction is reported and stays paused there
+            Ok(command) => {
+                match self
+                    .debugger
+                    .run_command(command, &mut self.chip8_processor, &self.keyboard)
+                {
+                    Ok(output) => println!("{}", output),
+                    Err(e) => {
+                        self.debugger.pause();
+                        eprintln!("{}", e);
+                    }
+                }
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    // save state problems are reported but never stop the game
+    fn handle_hotkey(&mut self, hotkey: Hotkey, rom: &str) {
+        match hotkey {
+            Hotkey::NextPalette => {
+                self.palette_index = (self.palette_index + 1) % Palette::PRESETS.len();
+                let (name, palette) = Palette::PRESETS[self.palette_index];
+                self.display_driver.renderer.set_palette(palette);
+                self.chip8_processor.vram_changed = true;
+                println!("Palette: {}", name);
+            }
+            Hotkey::ToggleCrt => {
+                let enabled = self.display_driver.renderer.toggle_crt();
+                self.chip8_processor.vram_changed = true;
+                println!("CRT filter {}", if enabled { "on" } else { "off" });
+            }
+            Hotkey::ToggleFullscreen => {
+                if let Err(e) = self.display_driver.toggle_fullscreen() {
+                    eprintln!("Could not toggle fullscreen: {}", e);
+                }
+            }
+            Hotkey::SaveState(slot) => {
+                let path = Emulator::save_state_path(rom, slot);
+                match fs::write(&path, self.chip8_processor.save_state()) {
+                    Ok(()) => println!("Saved state to {}", path),
+                    Err(e) => eprintln!("Could not save state to {}: {}", path, e),
+                }
+            }
+            Hotkey::LoadState(_) if !matches!(self.movie_mode, MovieMode::Off) => {
+                eprintln!("Save states can't be loaded while a movie is recording or playing");
+            }
+            Hotkey::LoadState(slot) => {
+                let path = Emulator::save_state_path(rom, slot);
+                let loaded = fs::read(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|state| {
+                        self.chip8_processor.load_state(&state)?;
+                        Ok(state)
+                    });
+                match loaded {
+                    Ok(state) => {
+                        // the history before the load leads somewhere else, rewind from here
+                        self.rewind_buffer.clear();
+                        self.rewind_buffer.push(state);
+                        println!("Loaded state from {}", path);
+                    }
+                    Err(e) => eprintln!("Could not load state from {}: {}", path, e),
+                }
+            }
+        }
+    }
+
+    // next to the ROM so a ROM and its states can be shared together, e.g. roms/Brick.ch8.state0
+    fn save_state_path(rom: &str, slot: u8) -> String {
+        format!("{}.state{}", rom, slot)
+    }
 }
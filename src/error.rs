@@ -0,0 +1,65 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can stop the interpreter. A bad ROM surfaces as one of these
+/// instead of a panic, so the frontend can halt and report where it went wrong.
+#[derive(Debug)]
+pub enum Chip8Error {
+    RomTooLarge { size: usize, max: usize },
+    RomIo(io::Error),
+    StackUnderflow { pc: u16 },
+    StackOverflow { pc: u16 },
+    InvalidOpcode { pc: u16, opcode: u16 },
+    MemoryOutOfBounds { addr: usize },
+    InvalidSaveState(&'static str),
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chip8Error::RomTooLarge { size, max } => {
+                write!(
+                    f,
+                    "ROM is {} bytes, at most {} bytes fit in memory",
+                    size, max
+                )
+            }
+            Chip8Error::RomIo(error) => write!(f, "could not read ROM: {}", error),
+            Chip8Error::StackUnderflow { pc } => {
+                write!(f, "return with an empty stack at {:#05x}", pc)
+            }
+            Chip8Error::StackOverflow { pc } => {
+                write!(f, "subroutine call overflowed the stack at {:#05x}", pc)
+            }
+            Chip8Error::InvalidOpcode { pc, opcode } => {
+                write!(f, "invalid opcode {:#06x} at {:#05x}", opcode, pc)
+            }
+            Chip8Error::MemoryOutOfBounds { addr } => {
+                write!(f, "memory access out of bounds at {:#x}", addr)
+            }
+            Chip8Error::InvalidSaveState(reason) => write!(f, "invalid save state: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Chip8Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Chip8Error::RomIo(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Chip8Error {
+    fn from(error: io::Error) -> Self {
+        Chip8Error::RomIo(error)
+    }
+}
+
+// the frontend still speaks `Result<_, String>` like the SDL calls it wraps
+impl From<Chip8Error> for String {
+    fn from(error: Chip8Error) -> Self {
+        error.to_string()
+    }
+}
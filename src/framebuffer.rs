@@ -0,0 +1,150 @@
+// the two sizes there are: CHIP-8's, and SUPER-CHIP hi-res which is also the biggest
+const LORES_SIZE: (u32, u32) = (64, 32);
+const HIRES_SIZE: (u32, u32) = (128, 64);
+const MAX_HEIGHT: u32 = HIRES_SIZE.1;
+
+/// One display bitplane, bit-packed: a `u128` per row with the leftmost pixel
+/// in the top bit, so sprites are drawn and collisions found a whole row at a time.
+/// Narrower displays only use the top `width` bits of each row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    rows: [u128; MAX_HEIGHT as usize],
+    width: u32,
+    height: u32,
+}
+
+impl Framebuffer {
+    /// 64x32, the CHIP-8 display.
+    pub fn lores() -> Framebuffer {
+        Framebuffer::new(LORES_SIZE)
+    }
+
+    /// 128x64, SUPER-CHIP and XO-CHIP hi-res mode.
+    pub fn hires() -> Framebuffer {
+        Framebuffer::new(HIRES_SIZE)
+    }
+
+    fn new((width, height): (u32, u32)) -> Framebuffer {
+        Framebuffer {
+            rows: [0; MAX_HEIGHT as usize],
+            width,
+            height,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height && self.rows[y as usize] >> (127 - x) & 1 == 1
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, on: bool) {
+        if x < self.width && y < self.height {
+            let bit = 1 << (127 - x);
+            if on {
+                self.rows[y as usize] |= bit;
+            } else {
+                self.rows[y as usize] &= !bit;
+            }
+        }
+    }
+
+    /// A row's pixels, leftmost in the top bit.
+    pub fn row(&self, y: u32) -> u128 {
+        self.rows[y as usize]
+    }
+
+    pub fn clear(&mut self) {
+        self.rows = [0; MAX_HEIGHT as usize];
+    }
+
+    /// XORs one sprite row of `sprite_width` (up to 16) pixels in at (x, y), either
+    /// cut off at the edges or wrapped around them. True if any pixel was turned off.
+    pub fn draw_row(&mut self, x: u32, y: u32, bits: u16, sprite_width: u32, clip: bool) -> bool {
+        if clip && y >= self.height {
+            return false;
+        }
+
+        let (x, y) = (x % self.width, y % self.height);
+        let placed = (bits as u128) << (128 - sprite_width);
+        let mut sprite = placed >> x;
+        if !clip && x + sprite_width > self.width {
+            // shifting left by the room that was left puts the overhang at the start of the row
+            sprite |= placed << (self.width - x);
+        }
+        sprite &= self.row_mask();
+
+        let row = &mut self.rows[y as usize];
+        let collision = *row & sprite != 0;
+        *row ^= sprite;
+        collision
+    }
+
+    /// Moves everything by (dx, dy), pixels scrolled in from the edge are off.
+    pub fn scroll(&mut self, dx: i32, dy: i32) {
+        let previous = self.rows;
+        let mask = self.row_mask();
+        for y in 0..self.height as i32 {
+            let source_y = y - dy;
+            self.rows[y as usize] = if (0..self.height as i32).contains(&source_y) {
+                let row = previous[source_y as usize];
+                match dx {
+                    0 => row,
+                    dx if dx > 0 => row >> dx,
+                    dx => row << -dx,
+                }
+            } else {
+                0
+            } & mask;
+        }
+    }
+
+    // the top `width` bits
+    fn row_mask(&self) -> u128 {
+        !u128::MAX.checked_shr(self.width).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn draw_row_test() {
+        let mut framebuffer = Framebuffer::lores();
+
+        assert!(!framebuffer.draw_row(62, 31, 0b1111_0000, 8, false));
+        assert!(framebuffer.get(62, 31) && framebuffer.get(63, 31));
+        assert!(framebuffer.get(0, 31) && framebuffer.get(1, 31));
+        assert!(!framebuffer.get(2, 31));
+
+        // drawing it again turns everything back off
+        assert!(framebuffer.draw_row(62, 31, 0b1111_0000, 8, false));
+        assert_eq!(0, framebuffer.row(31));
+
+        framebuffer.draw_row(62, 0, 0xFFFF, 16, true);
+        assert_eq!(0b11 << 64, framebuffer.row(0));
+        assert!(!framebuffer.draw_row(0, 40, 0xFF, 8, true));
+    }
+
+    #[test]
+    fn scroll_test() {
+        let mut framebuffer = Framebuffer::hires();
+        framebuffer.set(0, 0, true);
+        framebuffer.set(127, 63, true);
+
+        framebuffer.scroll(4, 2);
+
+        assert!(framebuffer.get(4, 2));
+        assert!(!framebuffer.get(127, 63));
+        framebuffer.scroll(-4, -2);
+        assert!(framebuffer.get(0, 0));
+    }
+}
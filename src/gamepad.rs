@@ -0,0 +1,40 @@
+extern crate sdl2;
+use sdl2::controller::GameController;
+use sdl2::GameControllerSubsystem;
+use std::collections::HashMap;
+
+/// The connected game controllers. SDL closes a controller when its handle is
+/// dropped, so they're kept here from the moment they're plugged in until
+/// they're pulled out.
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    // by joystick instance id, which is what button events carry
+    controllers: HashMap<u32, GameController>,
+}
+
+impl Gamepads {
+    pub fn new(subsystem: GameControllerSubsystem) -> Gamepads {
+        Gamepads {
+            subsystem,
+            controllers: HashMap::new(),
+        }
+    }
+
+    // SDL reports controllers that were already plugged in at startup as added too
+    pub fn connect(&mut self, joystick_index: u32) {
+        match self.subsystem.open(joystick_index) {
+            Ok(controller) => {
+                println!("Connected controller: {}", controller.name());
+                self.controllers
+                    .insert(controller.instance_id(), controller);
+            }
+            Err(e) => eprintln!("Could not open controller {}: {}", joystick_index, e),
+        }
+    }
+
+    pub fn disconnect(&mut self, instance_id: u32) {
+        if let Some(controller) = self.controllers.remove(&instance_id) {
+            println!("Disconnected controller: {}", controller.name());
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use crate::chip8::Chip8;
+use crate::error::Chip8Error;
+use std::fmt;
+
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: u32 = 15;
+
+/// A key going down or up at the start of a given frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// Where the machine ended up after a headless run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeadlessReport {
+    pub frames: u64,
+    /// one colour index per pixel, see `Chip8::pixel_color`
+    pub framebuffer: Vec<Vec<u8>>,
+    pub registers: [u8; 16],
+    pub index_register: u16,
+    pub program_counter: u16,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub halted: bool,
+}
+
+/// Drives a `Chip8` in 60 Hz frames with no window, audio or event pump,
+/// feeding it keys from a script instead.
+pub struct HeadlessRunner {
+    chip8: Chip8,
+    instructions_per_frame: u32,
+    input_script: Vec<KeyEvent>,
+    keypad: [bool; 16],
+    frame: u64,
+}
+
+impl HeadlessRunner {
+    pub fn new(chip8: Chip8, instructions_per_frame: u32) -> HeadlessRunner {
+        HeadlessRunner {
+            chip8,
+            instructions_per_frame,
+            input_script: Vec::new(),
+            keypad: [false; 16],
+            frame: 0,
+        }
+    }
+
+    pub fn set_input_script(&mut self, mut input_script: Vec<KeyEvent>) {
+        input_script.sort_by_key(|event| event.frame);
+        self.input_script = input_script;
+    }
+
+    pub fn chip8(&self) -> &Chip8 {
+        &self.chip8
+    }
+
+    pub fn chip8_mut(&mut self) -> &mut Chip8 {
+        &mut self.chip8
+    }
+
+    pub fn run_frame(&mut self) -> Result<(), Chip8Error> {
+        let frame = self.frame;
+        self.input_script
+            .iter()
+            .filter(|event| event.frame == frame && event.key < 16)
+            .for_each(|event| self.keypad[event.key as usize] = event.pressed);
+
+        for _ in 0..self.instructions_per_frame {
+            if self.chip8.is_halted() {
+                break;
+            }
+            self.chip8.handle_next_instruction(&self.keypad)?;
+        }
+
+        self.chip8.end_frame();
+        self.frame += 1;
+
+        Ok(())
+    }
+
+    pub fn run_frames(&mut self, frames: u64) -> Result<HeadlessReport, Chip8Error> {
+        self.run_until(frames, |_| false)
+    }
+
+    /// Runs until `condition` holds after a frame, the ROM exits, or `max_frames` have passed.
+    pub fn run_until(
+        &mut self,
+        max_frames: u64,
+        mut condition: impl FnMut(&Chip8) -> bool,
+    ) -> Result<HeadlessReport, Chip8Error> {
+        for _ in 0..max_frames {
+            self.run_frame()?;
+            if self.chip8.is_halted() || condition(&self.chip8) {
+                break;
+            }
+        }
+
+        Ok(self.report())
+    }
+
+    pub fn report(&self) -> HeadlessReport {
+        let framebuffer = (0..self.chip8.display_height() as usize)
+            .map(|y| {
+                (0..self.chip8.display_width() as usize)
+                    .map(|x| self.chip8.pixel_color(x, y))
+                    .collect()
+            })
+            .collect();
+
+        HeadlessReport {
+            frames: self.frame,
+            framebuffer,
+            registers: *self.chip8.get_registers(),
+            index_register: self.chip8.get_index_register(),
+            program_counter: self.chip8.get_program_counter(),
+            delay_timer: self.chip8.get_delay_timer(),
+            sound_timer: self.chip8.get_sound_timer(),
+            halted: self.chip8.is_halted(),
+        }
+    }
+}
+
+// plain text dump so CI logs can show what was on screen
+impl fmt::Display for HeadlessReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "frames: {} pc: {:#05x} i: {:#05x} dt: {} st: {} halted: {}",
+            self.frames,
+            self.program_counter,
+            self.index_register,
+            self.delay_timer,
+            self.sound_timer,
+            self.halted
+        )?;
+        for (register, value) in self.registers.iter().enumerate() {
+            write!(f, "V{:X}={:02x} ", register, value)?;
+        }
+        writeln!(f)?;
+
+        for row in &self.framebuffer {
+            let line: String = row
+                .iter()
+                .map(|&color| [' ', '#', '+', '*'][color as usize & 0b11])
+                .collect();
+            writeln!(f, "{}", line.trim_end())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn runner_with_program(program: &[u8]) -> HeadlessRunner {
+        let mut chip8 = Chip8::default();
+        chip8.load_rom_bytes(program).unwrap();
+        HeadlessRunner::new(chip8, DEFAULT_INSTRUCTIONS_PER_FRAME)
+    }
+
+    #[test]
+    fn scripted_key_press_test() {
+        // F10A waits for a key into V1, F129 and D005 draw its digit, then 1206 loops forever
+        let mut runner = runner_with_program(&[0xF1, 0x0A, 0xF1, 0x29, 0xD0, 0x05, 0x12, 0x06]);
+        runner.set_input_script(vec![
+            KeyEvent {
+                frame: 3,
+                key: 0x7,
+                pressed: true,
+            },
+            KeyEvent {
+                frame: 5,
+                key: 0x7,
+                pressed: false,
+            },
+        ]);
+
+        let report = runner
+            .run_until(60, |chip8| chip8.get_program_counter() == 0x206)
+            .unwrap();
+
+        assert_eq!(0x7, report.registers[1]);
+        assert_eq!(6, report.frames);
+        // the top row of the "7" glyph is 0xF0
+        assert_eq!([1, 1, 1, 1, 0], report.framebuffer[0][..5]);
+    }
+
+    #[test]
+    fn run_stops_at_exit_test() {
+        // V0 = 9, FX18, 00FD
+        let mut runner = runner_with_program(&[0x60, 0x09, 0xF0, 0x18, 0x00, 0xFD]);
+
+        let report = runner.run_frames(100).unwrap();
+
+        assert!(report.halted);
+        assert_eq!(1, report.frames);
+        assert_eq!(8, report.sound_timer);
+    }
+}
@@ -0,0 +1,388 @@
+use std::fmt;
+
+/// One decoded CHIP-8, SUPER-CHIP or XO-CHIP instruction. `x` and `y` are
+/// register numbers, `nn` a byte and `n` a nibble, as in the opcode tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0NNN, a machine code routine on the COSMAC VIP, ignored here. 0000 isn't one
+    Sys(u16),
+    /// 00E0
+    Cls,
+    /// 00EE
+    Ret,
+    /// 00CN
+    ScrollDown(u8),
+    /// 00DN
+    ScrollUp(u8),
+    /// 00FB
+    ScrollRight,
+    /// 00FC
+    ScrollLeft,
+    /// 00FD
+    Exit,
+    /// 00FE
+    LowRes,
+    /// 00FF
+    HighRes,
+    /// 1NNN
+    Jp(u16),
+    /// 2NNN
+    Call(u16),
+    /// 3XNN
+    SeImm { x: u8, nn: u8 },
+    /// 4XNN
+    SneImm { x: u8, nn: u8 },
+    /// 5XY0
+    SeReg { x: u8, y: u8 },
+    /// 5XY2
+    SaveRange { x: u8, y: u8 },
+    /// 5XY3
+    LoadRange { x: u8, y: u8 },
+    /// 6XNN
+    LdImm { x: u8, nn: u8 },
+    /// 7XNN
+    AddImm { x: u8, nn: u8 },
+    /// 8XY0
+    LdReg { x: u8, y: u8 },
+    /// 8XY1
+    Or { x: u8, y: u8 },
+    /// 8XY2
+    And { x: u8, y: u8 },
+    /// 8XY3
+    Xor { x: u8, y: u8 },
+    /// 8XY4
+    AddReg { x: u8, y: u8 },
+    /// 8XY5
+    Sub { x: u8, y: u8 },
+    /// 8XY6
+    Shr { x: u8, y: u8 },
+    /// 8XY7
+    Subn { x: u8, y: u8 },
+    /// 8XYE
+    Shl { x: u8, y: u8 },
+    /// 9XY0
+    SneReg { x: u8, y: u8 },
+    /// ANNN
+    LdI(u16),
+    /// BNNN, read as BXNN under the jump quirk
+    JpOffset(u16),
+    /// CXNN
+    Rnd { x: u8, nn: u8 },
+    /// DXYN
+    Drw { x: u8, y: u8, n: u8 },
+    /// EX9E
+    Skp(u8),
+    /// EXA1
+    Sknp(u8),
+    /// F000 NNNN
+    LdILong(u16),
+    /// F002
+    LdAudio,
+    /// FN01
+    Plane(u8),
+    /// FX07
+    LdVxDt(u8),
+    /// FX0A
+    LdKey(u8),
+    /// FX15
+    LdDtVx(u8),
+    /// FX18
+    LdStVx(u8),
+    /// FX1E
+    AddI(u8),
+    /// FX29
+    LdFont(u8),
+    /// FX30
+    LdBigFont(u8),
+    /// FX33
+    Bcd(u8),
+    /// FX3A
+    LdPitch(u8),
+    /// FX55
+    StoreRegs(u8),
+    /// FX65
+    LoadRegs(u8),
+    /// FX75
+    StoreFlags(u8),
+    /// FX85
+    LoadFlags(u8),
+}
+
+impl Instruction {
+    /// Decodes the instruction at the start of `bytes`. Returns `None` for an
+    /// unknown opcode, or an F000 without the two address bytes after it.
+    pub fn decode(bytes: &[u8]) -> Option<Instruction> {
+        let opcode = opcode_at(bytes, 0)?;
+        let x = ((opcode & 0x0F00) >> 8) as u8;
+        let y = ((opcode & 0x00F0) >> 4) as u8;
+        let nnn = opcode & 0x0FFF;
+        let nn = (opcode & 0x00FF) as u8;
+        let n = (opcode & 0x000F) as u8;
+
+        let instruction = match (opcode >> 12, x, y, n) {
+            (0x0, 0x0, 0xE, 0x0) => Instruction::Cls,
+            (0x0, 0x0, 0xE, 0xE) => Instruction::Ret,
+            (0x0, 0x0, 0xC, _) => Instruction::ScrollDown(n),
+            (0x0, 0x0, 0xD, _) => Instruction::ScrollUp(n),
+            (0x0, 0x0, 0xF, 0xB) => Instruction::ScrollRight,
+            (0x0, 0x0, 0xF, 0xC) => Instruction::ScrollLeft,
+            (0x0, 0x0, 0xF, 0xD) => Instruction::Exit,
+            (0x0, 0x0, 0xF, 0xE) => Instruction::LowRes,
+            (0x0, 0x0, 0xF, 0xF) => Instruction::HighRes,
+            // zeroed memory, a PC that ran off the program rather than a SYS
+            (0x0, 0x0, 0x0, 0x0) => return None,
+            (0x0, _, _, _) => Instruction::Sys(nnn),
+            (0x1, _, _, _) => Instruction::Jp(nnn),
+            (0x2, _, _, _) => Instruction::Call(nnn),
+            (0x3, _, _, _) => Instruction::SeImm { x, nn },
+            (0x4, _, _, _) => Instruction::SneImm { x, nn },
+            (0x5, _, _, 0x0) => Instruction::SeReg { x, y },
+            (0x5, _, _, 0x2) => Instruction::SaveRange { x, y },
+            (0x5, _, _, 0x3) => Instruction::LoadRange { x, y },
+            (0x6, _, _, _) => Instruction::LdImm { x, nn },
+            (0x7, _, _, _) => Instruction::AddImm { x, nn },
+            (0x8, _, _, 0x0) => Instruction::LdReg { x, y },
+            (0x8, _, _, 0x1) => Instruction::Or { x, y },
+            (0x8, _, _, 0x2) => Instruction::And { x, y },
+            (0x8, _, _, 0x3) => Instruction::Xor { x, y },
+            (0x8, _, _, 0x4) => Instruction::AddReg { x, y },
+            (0x8, _, _, 0x5) => Instruction::Sub { x, y },
+            (0x8, _, _, 0x6) => Instruction::Shr { x, y },
+            (0x8, _, _, 0x7) => Instruction::Subn { x, y },
+            (0x8, _, _, 0xE) => Instruction::Shl { x, y },
+            (0x9, _, _, 0x0) => Instruction::SneReg { x, y },
+            (0xA, _, _, _) => Instruction::LdI(nnn),
+            (0xB, _, _, _) => Instruction::JpOffset(nnn),
+            (0xC, _, _, _) => Instruction::Rnd { x, nn },
+            (0xD, _, _, _) => Instruction::Drw { x, y, n },
+            (0xE, _, 0x9, 0xE) => Instruction::Skp(x),
+            (0xE, _, 0xA, 0x1) => Instruction::Sknp(x),
+            (0xF, 0x0, 0x0, 0x0) => Instruction::LdILong(opcode_at(bytes, 2)?),
+            (0xF, 0x0, 0x0, 0x2) => Instruction::LdAudio,
+            (0xF, _, 0x0, 0x1) => Instruction::Plane(x),
+            (0xF, _, 0x0, 0x7) => Instruction::LdVxDt(x),
+            (0xF, _, 0x0, 0xA) => Instruction::LdKey(x),
+            (0xF, _, 0x1, 0x5) => Instruction::LdDtVx(x),
+            (0xF, _, 0x1, 0x8) => Instruction::LdStVx(x),
+            (0xF, _, 0x1, 0xE) => Instruction::AddI(x),
+            (0xF, _, 0x2, 0x9) => Instruction::LdFont(x),
+            (0xF, _, 0x3, 0x0) => Instruction::LdBigFont(x),
+            (0xF, _, 0x3, 0x3) => Instruction::Bcd(x),
+            (0xF, _, 0x3, 0xA) => Instruction::LdPitch(x),
+            (0xF, _, 0x5, 0x5) => Instruction::StoreRegs(x),
+            (0xF, _, 0x6, 0x5) => Instruction::LoadRegs(x),
+            (0xF, _, 0x7, 0x5) => Instruction::StoreFlags(x),
+            (0xF, _, 0x8, 0x5) => Instruction::LoadFlags(x),
+            _ => return None,
+        };
+
+        Some(instruction)
+    }
+
+    /// Length in bytes, 4 for F000 NNNN and 2 for everything else.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LdILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// The opcode bytes for this instruction, the inverse of `decode`.
+    pub fn encode(&self) -> Vec<u8> {
+        let xy = |high: u16, x: u8, y: u8, low: u16| {
+            (high << 12) | ((x as u16 & 0xF) << 8) | ((y as u16 & 0xF) << 4) | low
+        };
+        let xnn = |high: u16, x: u8, nn: u8| (high << 12) | ((x as u16 & 0xF) << 8) | nn as u16;
+        let fx = |x: u8, low: u16| 0xF000 | ((x as u16 & 0xF) << 8) | low;
+
+        let opcode = match *self {
+            Instruction::Sys(nnn) => nnn & 0x0FFF,
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+            Instruction::ScrollUp(n) => 0x00D0 | (n as u16 & 0xF),
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LowRes => 0x00FE,
+            Instruction::HighRes => 0x00FF,
+            Instruction::Jp(nnn) => 0x1000 | (nnn & 0x0FFF),
+            Instruction::Call(nnn) => 0x2000 | (nnn & 0x0FFF),
+            Instruction::SeImm { x, nn } => xnn(0x3, x, nn),
+            Instruction::SneImm { x, nn } => xnn(0x4, x, nn),
+            Instruction::SeReg { x, y } => xy(0x5, x, y, 0x0),
+            Instruction::SaveRange { x, y } => xy(0x5, x, y, 0x2),
+            Instruction::LoadRange { x, y } => xy(0x5, x, y, 0x3),
+            Instruction::LdImm { x, nn } => xnn(0x6, x, nn),
+            Instruction::AddImm { x, nn } => xnn(0x7, x, nn),
+            Instruction::LdReg { x, y } => xy(0x8, x, y, 0x0),
+            Instruction::Or { x, y } => xy(0x8, x, y, 0x1),
+            Instruction::And { x, y } => xy(0x8, x, y, 0x2),
+            Instruction::Xor { x, y } => xy(0x8, x, y, 0x3),
+            Instruction::AddReg { x, y } => xy(0x8, x, y, 0x4),
+            Instruction::Sub { x, y } => xy(0x8, x, y, 0x5),
+            Instruction::Shr { x, y } => xy(0x8, x, y, 0x6),
+            Instruction::Subn { x, y } => xy(0x8, x, y, 0x7),
+            Instruction::Shl { x, y } => xy(0x8, x, y, 0xE),
+            Instruction::SneReg { x, y } => xy(0x9, x, y, 0x0),
+            Instruction::LdI(nnn) => 0xA000 | (nnn & 0x0FFF),
+            Instruction::JpOffset(nnn) => 0xB000 | (nnn & 0x0FFF),
+            Instruction::Rnd { x, nn } => xnn(0xC, x, nn),
+            Instruction::Drw { x, y, n } => xy(0xD, x, y, n as u16 & 0xF),
+            Instruction::Skp(x) => xnn(0xE, x, 0x9E),
+            Instruction::Sknp(x) => xnn(0xE, x, 0xA1),
+            Instruction::LdILong(nnnn) => {
+                return [0xF0, 0x00, (nnnn >> 8) as u8, nnnn as u8].to_vec()
+            }
+            Instruction::LdAudio => 0xF002,
+            Instruction::Plane(n) => fx(n, 0x01),
+            Instruction::LdVxDt(x) => fx(x, 0x07),
+            Instruction::LdKey(x) => fx(x, 0x0A),
+            Instruction::LdDtVx(x) => fx(x, 0x15),
+            Instruction::LdStVx(x) => fx(x, 0x18),
+            Instruction::AddI(x) => fx(x, 0x1E),
+            Instruction::LdFont(x) => fx(x, 0x29),
+            Instruction::LdBigFont(x) => fx(x, 0x30),
+            Instruction::Bcd(x) => fx(x, 0x33),
+            Instruction::LdPitch(x) => fx(x, 0x3A),
+            Instruction::StoreRegs(x) => fx(x, 0x55),
+            Instruction::LoadRegs(x) => fx(x, 0x65),
+            Instruction::StoreFlags(x) => fx(x, 0x75),
+            Instruction::LoadFlags(x) => fx(x, 0x85),
+        };
+
+        opcode.to_be_bytes().to_vec()
+    }
+}
+
+fn opcode_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    let pair = bytes.get(offset..offset + 2)?;
+    Some(((pair[0] as u16) << 8) | pair[1] as u16)
+}
+
+// Cowgod's mnemonics, with made up ones in the same spirit for the XO-CHIP additions
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Sys(nnn) => write!(f, "SYS {:#05x}", nnn),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::ScrollDown(n) => write!(f, "SCD {}", n),
+            Instruction::ScrollUp(n) => write!(f, "SCU {}", n),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::LowRes => write!(f, "LOW"),
+            Instruction::HighRes => write!(f, "HIGH"),
+            Instruction::Jp(nnn) => write!(f, "JP {:#05x}", nnn),
+            Instruction::Call(nnn) => write!(f, "CALL {:#05x}", nnn),
+            Instruction::SeImm { x, nn } => write!(f, "SE V{:X}, {:#04x}", x, nn),
+            Instruction::SneImm { x, nn } => write!(f, "SNE V{:X}, {:#04x}", x, nn),
+            Instruction::SeReg { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::SaveRange { x, y } => write!(f, "LD [I], V{:X}-V{:X}", x, y),
+            Instruction::LoadRange { x, y } => write!(f, "LD V{:X}-V{:X}, [I]", x, y),
+            Instruction::LdImm { x, nn } => write!(f, "LD V{:X}, {:#04x}", x, nn),
+            Instruction::AddImm { x, nn } => write!(f, "ADD V{:X}, {:#04x}", x, nn),
+            Instruction::LdReg { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(nnn) => write!(f, "LD I, {:#05x}", nnn),
+            Instruction::JpOffset(nnn) => write!(f, "JP V0, {:#05x}", nnn),
+            Instruction::Rnd { x, nn } => write!(f, "RND V{:X}, {:#04x}", x, nn),
+            Instruction::Drw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdILong(nnnn) => write!(f, "LD I, LONG {:#06x}", nnnn),
+            Instruction::LdAudio => write!(f, "AUDIO"),
+            Instruction::Plane(n) => write!(f, "PLANE {}", n),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdKey(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdFont(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdBigFont(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::Bcd(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::LdPitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::StoreRegs(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LoadRegs(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn decode_test() {
+        assert_eq!(Some(Instruction::Cls), Instruction::decode(&[0x00, 0xE0]));
+        assert_eq!(
+            Some(Instruction::Sys(0x2A4)),
+            Instruction::decode(&[0x02, 0xA4])
+        );
+        assert_eq!(None, Instruction::decode(&[0x00, 0x00]));
+        assert_eq!(
+            Some(Instruction::Call(0x2A4)),
+            Instruction::decode(&[0x22, 0xA4])
+        );
+        assert_eq!(
+            Some(Instruction::Drw { x: 1, y: 2, n: 5 }),
+            Instruction::decode(&[0xD1, 0x25])
+        );
+        assert_eq!(
+            Some(Instruction::Bcd(0xC)),
+            Instruction::decode(&[0xFC, 0x33])
+        );
+        assert_eq!(
+            Some(Instruction::LdILong(0x1234)),
+            Instruction::decode(&[0xF0, 0x00, 0x12, 0x34])
+        );
+    }
+
+    #[test]
+    fn encode_round_trip_test() {
+        // every opcode that decodes has to encode back to the same bytes
+        for opcode in 0..=0xFFFFu16 {
+            let bytes = [(opcode >> 8) as u8, opcode as u8, 0xAB, 0xCD];
+            if let Some(instruction) = Instruction::decode(&bytes) {
+                let size = instruction.size() as usize;
+                assert_eq!(bytes[..size].to_vec(), instruction.encode());
+            }
+        }
+    }
+
+    #[test]
+    fn decode_unknown_opcode_test() {
+        assert_eq!(None, Instruction::decode(&[0x81, 0x2F]));
+        assert_eq!(None, Instruction::decode(&[0xE1, 0x00]));
+        assert_eq!(None, Instruction::decode(&[0x5A, 0xB1]));
+        // F000 with its address cut off
+        assert_eq!(None, Instruction::decode(&[0xF0, 0x00, 0x12]));
+        assert_eq!(None, Instruction::decode(&[0x60]));
+    }
+
+    #[test]
+    fn display_test() {
+        let mnemonics: Vec<String> = [
+            Instruction::LdImm { x: 0xA, nn: 0x0F },
+            Instruction::JpOffset(0x300),
+            Instruction::LoadRegs(3),
+        ]
+        .iter()
+        .map(|instruction| instruction.to_string())
+        .collect();
+
+        assert_eq!(vec!["LD VA, 0x0f", "JP V0, 0x300", "LD V3, [I]"], mnemonics);
+    }
+}
@@ -1,18 +1,78 @@
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::EventPump;
+use crate::gamepad::Gamepads;
+use crate::keymap::KeyMap;
+use crate::keypad::Keypad;
+use sdl2::controller::{Axis, Button};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::{EventPump, GameControllerSubsystem};
+use std::collections::{HashMap, HashSet};
 
+// how far the left stick has to be pushed to count as a d-pad press, out of 32767
+const STICK_DEAD_ZONE: i16 = 16000;
+
+// emulator controls that aren't CHIP-8 keys, collected while polling events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    SaveState(u8),
+    LoadState(u8),
+    ToggleFullscreen,
+    NextPalette,
+    ToggleCrt,
+}
+
+// something on the host that can hold a CHIP-8 key down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Input {
+    Key(Keycode),
+    // controller instance id and button
+    Button(u32, Button),
+    // the left stick pushed in a direction, bound like that d-pad button
+    Stick(u32, Button),
+}
+
+/// The CHIP-8 keypad as driven by the keyboard and any game controllers.
+#[derive(Default)]
 pub struct Keyboard {
     state: [bool; 16],
-    halt_key: usize,
+    key_bindings: HashMap<Keycode, u8>,
+    button_bindings: HashMap<Button, u8>,
+    // inputs that are down, a CHIP-8 key stays pressed while any of its inputs is
+    held: HashSet<Input>,
+    gamepads: Option<Gamepads>,
+    save_slot: u8,
+    hotkeys: Vec<Hotkey>,
+    rewinding: bool,
+    window_changed: bool,
 }
 
 impl Keyboard {
-    pub fn new() -> Keyboard {
-        Keyboard {
-            state: [false; 16],
-            halt_key: 256, //256 bc i want to embarrass bytes and their storage capacity
+    pub fn new(
+        keymap: &KeyMap,
+        game_controller: Option<GameControllerSubsystem>,
+    ) -> Result<Keyboard, String> {
+        let mut key_bindings = HashMap::new();
+        let mut button_bindings = HashMap::new();
+        for key in 0..16 {
+            for name in keymap.bindings(key) {
+                let keycode = Keycode::from_name(name).ok_or_else(|| {
+                    format!("unknown key '{}' bound to CHIP-8 key {:X}", name, key)
+                })?;
+                key_bindings.insert(keycode, key);
+            }
+            for name in keymap.button_bindings(key) {
+                let button = Button::from_string(&name.to_ascii_lowercase()).ok_or_else(|| {
+                    format!("unknown button '{}' bound to CHIP-8 key {:X}", name, key)
+                })?;
+                button_bindings.insert(button, key);
+            }
         }
+
+        Ok(Keyboard {
+            key_bindings,
+            button_bindings,
+            gamepads: game_controller.map(Gamepads::new),
+            ..Keyboard::default()
+        })
     }
 
     pub fn parse_keyboard_event(&mut self, event_pump: &mut EventPump) -> bool {
@@ -23,22 +83,97 @@ impl Keyboard {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => return false,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => self.hotkeys.push(Hotkey::SaveState(self.save_slot)),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => self.hotkeys.push(Hotkey::LoadState(self.save_slot)),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    repeat: false,
+                    ..
+                } => self.hotkeys.push(Hotkey::NextPalette),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    repeat: false,
+                    ..
+                } => self.hotkeys.push(Hotkey::ToggleCrt),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => self.hotkeys.push(Hotkey::ToggleFullscreen),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Return),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => {
+                    self.hotkeys.push(Hotkey::ToggleFullscreen)
+                }
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..) | WindowEvent::Exposed,
+                    ..
+                } => self.window_changed = true,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => self.rewinding = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => self.rewinding = false,
                 Event::KeyDown {
                     keycode: Some(keycode),
+                    keymod,
                     ..
-                } => {
-                    if let Some(key) = Keyboard::get_key_pressed(keycode) {
-                        self.state[key] = true;
+                } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                    // ctrl + 0-9 picks the save state slot
+                    if let Some(slot) = Keyboard::get_digit_pressed(keycode) {
+                        self.save_slot = slot;
                     }
                 }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => self.press(Input::Key(keycode)),
                 Event::KeyUp {
                     keycode: Some(keycode),
                     ..
-                } => {
-                    if let Some(key) = Keyboard::get_key_pressed(keycode) {
-                        self.state[key] = false;
+                } => self.release(Input::Key(keycode)),
+                Event::ControllerDeviceAdded { which, .. } => {
+                    if let Some(gamepads) = &mut self.gamepads {
+                        gamepads.connect(which);
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some(gamepads) = &mut self.gamepads {
+                        gamepads.disconnect(which);
                     }
+                    // nothing will ever release what it was holding
+                    let held: Vec<Input> = self.held.iter().copied().collect();
+                    for input in held {
+                        if let Input::Button(id, _) | Input::Stick(id, _) = input {
+                            if id == which {
+                                self.release(input);
+                            }
+                        }
+                    }
+                }
+                Event::ControllerButtonDown { which, button, .. } => {
+                    self.press(Input::Button(which, button))
+                }
+                Event::ControllerButtonUp { which, button, .. } => {
+                    self.release(Input::Button(which, button))
                 }
+                Event::ControllerAxisMotion {
+                    which, axis, value, ..
+                } => self.move_stick(which, axis, value),
                 _ => {}
             }
         }
@@ -50,15 +185,66 @@ impl Keyboard {
         &self.state
     }
 
-    pub fn set_halt_key(&mut self, key: u8) {
-        self.halt_key = key as usize;
+    // backspace is held down
+    pub fn is_rewinding(&self) -> bool {
+        self.rewinding
     }
 
-    pub fn get_halt_key(&self) -> usize {
-        self.halt_key as usize
+    pub fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        std::mem::take(&mut self.hotkeys)
     }
 
-    fn get_key_pressed(key_pressed: Keycode) -> Option<usize> {
+    // the window was resized or uncovered since the last call
+    pub fn take_window_changed(&mut self) -> bool {
+        std::mem::take(&mut self.window_changed)
+    }
+
+    fn input_key(&self, input: Input) -> Option<u8> {
+        match input {
+            Input::Key(keycode) => self.key_bindings.get(&keycode).copied(),
+            Input::Button(_, button) | Input::Stick(_, button) => {
+                self.button_bindings.get(&button).copied()
+            }
+        }
+    }
+
+    fn press(&mut self, input: Input) {
+        if let Some(key) = self.input_key(input) {
+            self.held.insert(input);
+            self.state[key as usize] = true;
+        }
+    }
+
+    fn release(&mut self, input: Input) {
+        if let Some(key) = self.input_key(input) {
+            self.held.remove(&input);
+            self.state[key as usize] = self
+                .held
+                .iter()
+                .any(|&held| self.input_key(held) == Some(key));
+        }
+    }
+
+    fn move_stick(&mut self, which: u32, axis: Axis, value: i16) {
+        let (negative, positive) = match axis {
+            Axis::LeftX => (Button::DPadLeft, Button::DPadRight),
+            Axis::LeftY => (Button::DPadUp, Button::DPadDown),
+            _ => return,
+        };
+
+        for (direction, pushed) in [
+            (negative, value < -STICK_DEAD_ZONE),
+            (positive, value > STICK_DEAD_ZONE),
+        ] {
+            if pushed {
+                self.press(Input::Stick(which, direction));
+            } else {
+                self.release(Input::Stick(which, direction));
+            }
+        }
+    }
+
+    fn get_digit_pressed(key_pressed: Keycode) -> Option<u8> {
         match key_pressed {
             Keycode::Num0 => Some(0),
             Keycode::Num1 => Some(1),
@@ -70,13 +256,13 @@ impl Keyboard {
             Keycode::Num7 => Some(7),
             Keycode::Num8 => Some(8),
             Keycode::Num9 => Some(9),
-            Keycode::A => Some(10),
-            Keycode::B => Some(11),
-            Keycode::C => Some(12),
-            Keycode::D => Some(13),
-            Keycode::E => Some(14),
-            Keycode::F => Some(15),
             _ => None,
         }
     }
 }
+
+impl Keypad for Keyboard {
+    fn is_key_pressed(&self, key: u8) -> bool {
+        self.state.is_key_pressed(key)
+    }
+}
@@ -0,0 +1,206 @@
+use crate::config::{Config, Setting};
+
+// CHIP-8 keys in the COSMAC VIP's hex pad order, row by row:
+// 1 2 3 C / 4 5 6 D / 7 8 9 E / A 0 B F
+const COSMAC_PAD: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+// the same spots on a QWERTY keyboard
+const COSMAC_HOST_KEYS: [&str; 16] = [
+    "1", "2", "3", "4", "Q", "W", "E", "R", "A", "S", "D", "F", "Z", "X", "C", "V",
+];
+
+// up/left/down/right are W/A/S/D in the COSMAC layout, which is 5/7/8/9, the
+// usual CHIP-8 directions. Brick and friends steer with 4 and 6.
+const DEFAULT_BUTTONS: [(&str, u8); 10] = [
+    ("dpup", 0x5),
+    ("dpleft", 0x7),
+    ("dpdown", 0x8),
+    ("dpright", 0x9),
+    ("a", 0x6),
+    ("b", 0x4),
+    ("x", 0x1),
+    ("y", 0x2),
+    ("back", 0x0),
+    ("start", 0xF),
+];
+
+// host inputs for each CHIP-8 key
+type Bindings = [Vec<String>; 16];
+
+/// Which host keys and gamepad buttons press which CHIP-8 key, any number of
+/// them per key.
+///
+/// Inputs go by their SDL names ("Q", "Space", "Left", "Keypad 5" for keys,
+/// "a", "dpup", "leftshoulder" for buttons) so the core doesn't depend on SDL;
+/// the frontend looks the names up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMap {
+    keys: Bindings,
+    buttons: Bindings,
+}
+
+impl KeyMap {
+    /// 0-9 and A-F press the key they're labelled with.
+    pub fn hex() -> KeyMap {
+        KeyMap {
+            keys: std::array::from_fn(|key| vec![format!("{:X}", key)]),
+            buttons: KeyMap::default_buttons(),
+        }
+    }
+
+    /// The hex pad's layout laid over 1234/QWER/ASDF/ZXCV, what most ROMs expect.
+    pub fn cosmac() -> KeyMap {
+        let mut keys = Bindings::default();
+        for (&key, host_key) in COSMAC_PAD.iter().zip(COSMAC_HOST_KEYS) {
+            keys[key as usize].push(host_key.to_string());
+        }
+
+        KeyMap {
+            keys,
+            buttons: KeyMap::default_buttons(),
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<KeyMap> {
+        match name {
+            "hex" => Some(KeyMap::hex()),
+            "cosmac" => Some(KeyMap::cosmac()),
+            _ => None,
+        }
+    }
+
+    fn default_buttons() -> Bindings {
+        let mut buttons = Bindings::default();
+        for (button, key) in DEFAULT_BUTTONS {
+            buttons[key as usize].push(button.to_string());
+        }
+        buttons
+    }
+
+    pub fn bindings(&self, key: u8) -> &[String] {
+        &self.keys[key as usize & 0xF]
+    }
+
+    pub fn button_bindings(&self, key: u8) -> &[String] {
+        &self.buttons[key as usize & 0xF]
+    }
+
+    /// Binds `key` to exactly these host keys, taking them away from any other key.
+    pub fn bind(&mut self, key: u8, host_keys: &[String]) {
+        KeyMap::rebind(&mut self.keys, key, host_keys);
+    }
+
+    /// Binds `key` to exactly these gamepad buttons, taking them away from any other key.
+    pub fn bind_buttons(&mut self, key: u8, buttons: &[String]) {
+        KeyMap::rebind(&mut self.buttons, key, buttons);
+    }
+
+    fn rebind(bindings: &mut Bindings, key: u8, inputs: &[String]) {
+        for bound in bindings.iter_mut() {
+            bound.retain(|bound| !inputs.iter().any(|input| input.eq_ignore_ascii_case(bound)));
+        }
+        bindings[key as usize & 0xF] = inputs.to_vec();
+    }
+
+    /// Applies `keys = preset`, `key.N = host keys...` and `button.N = buttons...`
+    /// settings, in order. A layout preset only replaces the keyboard bindings.
+    pub fn configure(&mut self, config: &Config, settings: &[&Setting]) -> Result<(), String> {
+        for setting in settings {
+            let inputs: Vec<String> = setting.value.split_whitespace().map(String::from).collect();
+            // Config only lets single hex digits through after the dot
+            let key = |digit| u8::from_str_radix(digit, 16).unwrap();
+
+            if setting.name == "keys" {
+                self.keys = KeyMap::preset(&setting.value)
+                    .ok_or_else(|| {
+                        config.error(
+                            setting.line,
+                            &format!("unknown key layout '{}', use cosmac or hex", setting.value),
+                        )
+                    })?
+                    .keys;
+            } else if let Some(digit) = setting.name.strip_prefix("key.") {
+                self.bind(key(digit), &inputs);
+            } else if let Some(digit) = setting.name.strip_prefix("button.") {
+                self.bind_buttons(key(digit), &inputs);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        KeyMap::cosmac()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn cosmac_layout_test() {
+        let keymap = KeyMap::cosmac();
+
+        assert_eq!(["X"], keymap.bindings(0x0));
+        assert_eq!(["4"], keymap.bindings(0xC));
+        assert_eq!(["V"], keymap.bindings(0xF));
+        assert_eq!(["A"], KeyMap::hex().bindings(0xA));
+        assert_eq!(["dpup"], keymap.button_bindings(0x5));
+    }
+
+    #[test]
+    fn configure_keymap_test() {
+        let config = Config::parse(
+            "chip8.conf",
+            "keys = hex\nkey.5 = W Up\nkey.6 = w\nbutton.4 = dpleft",
+        )
+        .unwrap();
+        let mut keymap = KeyMap::default();
+        keymap
+            .configure(&config, &config.settings("Game.ch8"))
+            .unwrap();
+
+        assert_eq!(["Up"], keymap.bindings(0x5));
+        assert_eq!(["w"], keymap.bindings(0x6));
+        assert_eq!(["0"], keymap.bindings(0x0));
+        assert_eq!(["dpleft"], keymap.button_bindings(0x4));
+        assert!(keymap.button_bindings(0x7).is_empty());
+
+        let config = Config::parse("chip8.conf", "keys = dvorak").unwrap();
+        assert!(keymap
+            .configure(&config, &config.settings("Game.ch8"))
+            .is_err());
+    }
+
+    #[test]
+    fn configure_buttons_test() {
+        let config = Config::parse(
+            "chip8.conf",
+            "button.4 = a dpleft\n[Brick.ch8]\nbutton.6 = a\nkeys = hex",
+        )
+        .unwrap();
+
+        let mut keymap = KeyMap::default();
+        keymap
+            .configure(&config, &config.settings("Other.ch8"))
+            .unwrap();
+        assert_eq!(["a", "dpleft"], keymap.button_bindings(0x4));
+        // both buttons were taken away from the keys they pressed before
+        assert!(keymap.button_bindings(0x6).is_empty());
+        assert!(keymap.button_bindings(0x7).is_empty());
+
+        let mut keymap = KeyMap::default();
+        keymap
+            .configure(&config, &config.settings("Brick.ch8"))
+            .unwrap();
+        assert_eq!(["dpleft"], keymap.button_bindings(0x4));
+        assert_eq!(["a"], keymap.button_bindings(0x6));
+        // a keyboard layout leaves the buttons alone
+        assert_eq!(["dpup"], keymap.button_bindings(0x5));
+    }
+}
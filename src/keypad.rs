@@ -0,0 +1,11 @@
+/// The 16-key hex keypad as seen by the interpreter. Frontends implement this
+/// so `Chip8` never has to know where key state comes from (SDL, a script, a test, ...).
+pub trait Keypad {
+    fn is_key_pressed(&self, key: u8) -> bool;
+}
+
+impl Keypad for [bool; 16] {
+    fn is_key_pressed(&self, key: u8) -> bool {
+        self.get(key as usize).copied().unwrap_or(false)
+    }
+}
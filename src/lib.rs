@@ -0,0 +1,35 @@
+pub mod assembler;
+pub mod chip8;
+pub mod cli;
+pub mod config;
+pub mod crt;
+pub mod debugger;
+pub mod disassembler;
+pub mod error;
+pub mod framebuffer;
+pub mod headless;
+pub mod instruction;
+pub mod keymap;
+pub mod keypad;
+pub mod movie;
+pub mod octo;
+pub mod palette;
+pub mod phosphor;
+pub mod quirks;
+pub mod random;
+pub mod rewind;
+pub mod save_state;
+pub mod viewport;
+
+#[cfg(feature = "frontend")]
+pub mod audio;
+#[cfg(feature = "frontend")]
+pub mod display;
+#[cfg(feature = "frontend")]
+pub mod emulator;
+#[cfg(feature = "frontend")]
+pub mod gamepad;
+#[cfg(feature = "frontend")]
+pub mod keyboard;
+#[cfg(feature = "frontend")]
+pub mod renderer;
@@ -1,16 +1,29 @@
-extern crate sdl2;
-mod audio;
-mod chip8;
-mod display;
-mod emulator;
-mod keyboard;
-mod pixel;
-mod renderer;
-use crate::emulator::Emulator;
+use chip8_emulator::cli::{self, Command};
+use chip8_emulator::emulator::Emulator;
+use std::env;
+use std::process;
 
 pub fn main() -> Result<(), String> {
-    let mut emulator = Emulator::build()?;
-    emulator.run(String::from("roms/Brick.ch8"))?;
+    // usage mistakes get a plain message rather than main's debug formatted error
+    let command = Command::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("chip8-emulator: {}", e);
+        process::exit(2);
+    });
 
+    let options = match command {
+        Command::Help => {
+            println!("{}", cli::HELP);
+            return Ok(());
+        }
+        Command::Run(options) => options,
+    };
+
+    if let Some(frames) = options.headless_frames {
+        print!("{}", cli::run_headless(&options, frames)?);
+        return Ok(());
+    }
+
+    let mut emulator = Emulator::build(&options)?;
+    emulator.run(options.rom)?;
     Ok(())
 }
@@ -0,0 +1,240 @@
+use crate::headless::KeyEvent;
+use crate::quirks::{IndexIncrement, Quirks};
+use std::fmt;
+
+const MOVIE_HEADER: &str = "chip8-movie 1";
+
+/// A recorded run: everything needed to replay it exactly, i.e. the machine
+/// setup plus the keypad state for every 60 Hz frame.
+///
+/// Saved as text so it can be attached to a bug report and read in a diff.
+/// Frames are run-length encoded, one `count mask` line per stretch of
+/// identical input, where bit N of the hex mask is key N.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub instructions_per_frame: u32,
+    /// FNV-1a of the program, to catch a movie being replayed against the wrong ROM
+    pub rom_checksum: u32,
+    frames: Vec<u16>,
+}
+
+impl Movie {
+    pub fn new(program: &[u8], seed: u64, quirks: Quirks, instructions_per_frame: u32) -> Movie {
+        Movie {
+            seed,
+            quirks,
+            instructions_per_frame,
+            rom_checksum: Movie::checksum(program),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Appends the keypad state the next frame ran with.
+    pub fn record_frame(&mut self, keys: &[bool; 16]) {
+        let mask = keys
+            .iter()
+            .enumerate()
+            .fold(0u16, |mask, (key, &pressed)| mask | (pressed as u16) << key);
+        self.frames.push(mask);
+    }
+
+    /// The keypad state for a frame, None once the movie has run out.
+    pub fn frame_keys(&self, frame: usize) -> Option<[bool; 16]> {
+        let mask = *self.frames.get(frame)?;
+        Some(std::array::from_fn(|key| mask >> key & 1 == 1))
+    }
+
+    /// The movie as key presses and releases, for `HeadlessRunner::set_input_script`.
+    pub fn key_events(&self) -> Vec<KeyEvent> {
+        let mut events = Vec::new();
+        let mut previous = 0u16;
+        for (frame, &mask) in self.frames.iter().enumerate() {
+            for key in 0..16 {
+                if (mask ^ previous) >> key & 1 == 1 {
+                    events.push(KeyEvent {
+                        frame: frame as u64,
+                        key,
+                        pressed: mask >> key & 1 == 1,
+                    });
+                }
+            }
+            previous = mask;
+        }
+
+        events
+    }
+
+    pub fn check_rom(&self, program: &[u8]) -> Result<(), String> {
+        if Movie::checksum(program) != self.rom_checksum {
+            return Err(String::from("the movie was recorded with a different ROM"));
+        }
+
+        Ok(())
+    }
+
+    pub fn checksum(program: &[u8]) -> u32 {
+        program.iter().fold(0x811C_9DC5, |hash, &byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        })
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty());
+        if lines.next().map(|(_, line)| line) != Some(MOVIE_HEADER) {
+            return Err(String::from("not a CHIP-8 movie"));
+        }
+
+        let mut movie = Movie::new(&[], 0, Quirks::default(), 0);
+        let mut has_frames = false;
+        for (line_number, line) in lines {
+            let error = |message: &str| format!("line {}: {}", line_number, message);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                ["seed", seed] => movie.seed = seed.parse().map_err(|_| error("bad seed"))?,
+                ["ipf", ipf] => {
+                    movie.instructions_per_frame = ipf.parse().map_err(|_| error("bad ipf"))?
+                }
+                ["rom", checksum] => {
+                    movie.rom_checksum =
+                        u32::from_str_radix(checksum, 16).map_err(|_| error("bad checksum"))?
+                }
+                ["quirk", name, value] => {
+                    Movie::parse_quirk(&mut movie.quirks, name, value).map_err(|e| error(&e))?
+                }
+                ["frames"] => has_frames = true,
+                [count, mask] if has_frames => {
+                    let count: usize = count.parse().map_err(|_| error("bad frame count"))?;
+                    let mask = u16::from_str_radix(mask, 16).map_err(|_| error("bad key mask"))?;
+                    movie.frames.extend(std::iter::repeat_n(mask, count));
+                }
+                _ => return Err(error(&format!("don't know what '{}' means", line))),
+            }
+        }
+
+        if movie.instructions_per_frame == 0 {
+            return Err(String::from("movie has no ipf"));
+        }
+
+        Ok(movie)
+    }
+
+    fn parse_quirk(quirks: &mut Quirks, name: &str, value: &str) -> Result<(), String> {
+        if name == "load_store_index" {
+            quirks.load_store_index = match value {
+                "unchanged" => IndexIncrement::Unchanged,
+                "x" => IndexIncrement::ByX,
+                "x+1" => IndexIncrement::ByXPlusOne,
+                _ => return Err(format!("bad load_store_index '{}'", value)),
+            };
+            return Ok(());
+        }
+
+        let flag = match name {
+            "shift_uses_vy" => &mut quirks.shift_uses_vy,
+            "jump_uses_vx" => &mut quirks.jump_uses_vx,
+            "logic_resets_vf" => &mut quirks.logic_resets_vf,
+            "sprites_clip" => &mut quirks.sprites_clip,
+            "display_wait" => &mut quirks.display_wait,
+            _ => return Err(format!("unknown quirk '{}'", name)),
+        };
+        *flag = value
+            .parse()
+            .map_err(|_| format!("quirk {} has to be true or false", name))?;
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", MOVIE_HEADER)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "ipf {}", self.instructions_per_frame)?;
+        writeln!(f, "rom {:08x}", self.rom_checksum)?;
+
+        let quirks = &self.quirks;
+        writeln!(f, "quirk shift_uses_vy {}", quirks.shift_uses_vy)?;
+        let load_store_index = match quirks.load_store_index {
+            IndexIncrement::Unchanged => "unchanged",
+            IndexIncrement::ByX => "x",
+            IndexIncrement::ByXPlusOne => "x+1",
+        };
+        writeln!(f, "quirk load_store_index {}", load_store_index)?;
+        writeln!(f, "quirk jump_uses_vx {}", quirks.jump_uses_vx)?;
+        writeln!(f, "quirk logic_resets_vf {}", quirks.logic_resets_vf)?;
+        writeln!(f, "quirk sprites_clip {}", quirks.sprites_clip)?;
+        writeln!(f, "quirk display_wait {}", quirks.display_wait)?;
+
+        writeln!(f, "frames")?;
+        for run in self.frames.chunk_by(|a, b| a == b) {
+            writeln!(f, "{} {:04x}", run.len(), run[0])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn movie_round_trip_test() {
+        let mut movie = Movie::new(&[0x12, 0x00], 99, Quirks::SUPER_CHIP, 30);
+        let mut keys = [false; 16];
+        movie.record_frame(&keys);
+        movie.record_frame(&keys);
+        keys[0xA] = true;
+        movie.record_frame(&keys);
+
+        let text = movie.to_string();
+        assert!(text.ends_with("frames\n2 0000\n1 0400\n"));
+        assert_eq!(Ok(movie.clone()), Movie::parse(&text));
+        assert_eq!(Some(keys), movie.frame_keys(2));
+        assert_eq!(None, movie.frame_keys(3));
+    }
+
+    #[test]
+    fn movie_key_events_test() {
+        let mut movie = Movie::new(&[], 0, Quirks::default(), 15);
+        let mut keys = [false; 16];
+        movie.record_frame(&keys);
+        keys[5] = true;
+        movie.record_frame(&keys);
+        movie.record_frame(&keys);
+        keys[5] = false;
+        movie.record_frame(&keys);
+
+        assert_eq!(
+            vec![
+                KeyEvent {
+                    frame: 1,
+                    key: 5,
+                    pressed: true
+                },
+                KeyEvent {
+                    frame: 3,
+                    key: 5,
+                    pressed: false
+                },
+            ],
+            movie.key_events()
+        );
+        assert!(Movie::parse("chip8-movie 1\nipf 15\nquirk turbo true\n").is_err());
+    }
+}
@@ -0,0 +1,1122 @@
+use crate::chip8::PROGRAM_START;
+use crate::instruction::Instruction;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+// a macro that expands into itself would otherwise never finish
+const MAX_MACRO_EXPANSIONS: usize = 10_000;
+const VF: u8 = 0xF;
+
+/// A problem in Octo source, with the line it was found on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OctoError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for OctoError {}
+
+impl From<OctoError> for String {
+    fn from(error: OctoError) -> Self {
+        error.to_string()
+    }
+}
+
+/// Compiles Octo source into a ROM that starts at 0x200. Execution starts at `: main`.
+pub fn compile(source: &str) -> Result<Vec<u8>, OctoError> {
+    let mut compiler = Compiler::new(source);
+    compiler.compile()?;
+    Ok(compiler.rom)
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    source
+        .lines()
+        .enumerate()
+        .flat_map(|(index, line)| {
+            let code = line.split('#').next().unwrap_or_default();
+            code.split_whitespace().map(move |text| Token {
+                text: text.to_string(),
+                line: index + 1,
+            })
+        })
+        .collect()
+}
+
+// what to write into an already emitted instruction once a forward label is known
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    // the NNN of a 1NNN/2NNN/ANNN/BNNN
+    Address,
+    // a full 16 bit address, for `i := long` and `:pointer`
+    Long,
+    // the two `vX := NN` of an `:unpack`, high nibble given
+    Unpack(u8),
+    // `:unpack long`, v0 gets the high byte and v1 the low byte
+    UnpackLong,
+}
+
+enum Block {
+    Loop { start: u16, breaks: Vec<usize> },
+    If { jump: usize },
+    Else { jump: usize },
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Key,
+    NotKey,
+}
+
+impl Comparison {
+    fn inverse(self) -> Comparison {
+        match self {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::Less => Comparison::GreaterEqual,
+            Comparison::GreaterEqual => Comparison::Less,
+            Comparison::Greater => Comparison::LessEqual,
+            Comparison::LessEqual => Comparison::Greater,
+            Comparison::Key => Comparison::NotKey,
+            Comparison::NotKey => Comparison::Key,
+        }
+    }
+}
+
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    rom: Vec<u8>,
+    pc: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<(usize, String, Fixup, usize)>,
+    blocks: Vec<Block>,
+    // set by `:next`, labels the second byte of the next instruction
+    next_label: Option<String>,
+    expansions: usize,
+}
+
+impl Compiler {
+    fn new(source: &str) -> Compiler {
+        Compiler {
+            tokens: tokenize(source),
+            line: 1,
+            rom: Vec::new(),
+            pc: PROGRAM_START,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            next_label: None,
+            expansions: 0,
+        }
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, OctoError> {
+        Err(OctoError {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn compile(&mut self) -> Result<(), OctoError> {
+        // programs that don't open with `: main` get a jump to it
+        let starts_with_main = matches!(
+            (self.tokens.front(), self.tokens.get(1)),
+            (Some(colon), Some(name)) if colon.text == ":" && name.text == "main"
+        );
+        if !starts_with_main {
+            self.reference(String::from("main"), Fixup::Address);
+            self.instruction(Instruction::Jp(0))?;
+        }
+
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+
+        if let Some(block) = self.blocks.last() {
+            let unclosed = match block {
+                Block::Loop { .. } => "'loop' without 'again'",
+                Block::If { .. } | Block::Else { .. } => "'begin' without 'end'",
+            };
+            return self.error(unclosed);
+        }
+        if !self.labels.contains_key("main") {
+            return self.error("the program doesn't define ': main'");
+        }
+
+        for (addr, label, fixup, line) in std::mem::take(&mut self.fixups) {
+            self.line = line;
+            let Some(&target) = self.labels.get(&label) else {
+                return self.error(format!("'{}' is never defined", label));
+            };
+            self.patch(addr, target, fixup)?;
+        }
+
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<String, OctoError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token.text)
+            }
+            None => self.error("unexpected end of program"),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), OctoError> {
+        let token = self.next()?;
+        if token != expected {
+            return self.error(format!("expected '{}', found '{}'", expected, token));
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), OctoError> {
+        let token = self.next()?;
+
+        if let Some(x) = self.register_named(&token) {
+            return self.assignment(x);
+        }
+        // a bare label is a call, so only numbers and constants are data
+        if let Some(value) = self.constant_named(&token) {
+            return self.data(&token, value);
+        }
+        if self.macros.contains_key(&token) {
+            return self.expand_macro(&token);
+        }
+
+        match token.as_str() {
+            ":" => {
+                let name = self.next()?;
+                self.define_label(name, self.pc)
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.next_label = Some(name);
+                Ok(())
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.next()?;
+                let value = match self.number_named(&value) {
+                    Some(value) => value,
+                    None => return self.error(format!("'{}' is not a number", value)),
+                };
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let x = self.register()?;
+                self.aliases.insert(name, x);
+                Ok(())
+            }
+            ":calc" => {
+                let name = self.next()?;
+                let value = self.braced_calc()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    self.braced_calc()?
+                } else {
+                    let token = self.next()?;
+                    self.number(&token)?
+                };
+                let byte = self.to_byte(value)?;
+                self.emit(&[byte])
+            }
+            ":pointer" => {
+                let label = self.next()?;
+                self.long_address(label)?;
+                Ok(())
+            }
+            ":org" => {
+                let token = self.next()?;
+                let value = self.number(&token)?;
+                if !(PROGRAM_START as f64..=0xFFFF as f64).contains(&value) {
+                    return self.error(format!("can't :org to {}", token));
+                }
+                self.pc = value as usize;
+                Ok(())
+            }
+            ":unpack" => {
+                let high = self.next()?;
+                let nibble = if high == "long" {
+                    None
+                } else {
+                    let value = self.number(&high)?;
+                    Some(self.to_nibble(value)?)
+                };
+                let label = self.next()?;
+                self.unpack(nibble, label)
+            }
+            ":call" => {
+                let label = self.next()?;
+                self.address_instruction(label, Instruction::Call)
+            }
+            ":macro" => self.define_macro(),
+            ":breakpoint" => self.next().map(|_| ()),
+            ":monitor" => self.next().and_then(|_| self.next()).map(|_| ()),
+            "jump" => {
+                let label = self.next()?;
+                self.address_instruction(label, Instruction::Jp)
+            }
+            "jump0" => {
+                let label = self.next()?;
+                self.address_instruction(label, Instruction::JpOffset)
+            }
+            "return" | ";" => self.instruction(Instruction::Ret),
+            "clear" => self.instruction(Instruction::Cls),
+            "exit" => self.instruction(Instruction::Exit),
+            "lores" => self.instruction(Instruction::LowRes),
+            "hires" => self.instruction(Instruction::HighRes),
+            "scroll-left" => self.instruction(Instruction::ScrollLeft),
+            "scroll-right" => self.instruction(Instruction::ScrollRight),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.instruction(Instruction::ScrollDown(n))
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.instruction(Instruction::ScrollUp(n))
+            }
+            "audio" => self.instruction(Instruction::LdAudio),
+            "plane" => {
+                let n = self.nibble()?;
+                if n > 3 {
+                    return self.error("plane has to be 0 to 3");
+                }
+                self.instruction(Instruction::Plane(n))
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.instruction(Instruction::Bcd(x))
+            }
+            "save" | "load" => self.save_load(&token),
+            "saveflags" => {
+                let x = self.register()?;
+                self.instruction(Instruction::StoreFlags(x))
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.instruction(Instruction::LoadFlags(x))
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.instruction(Instruction::Drw { x, y, n })
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.instruction(match token.as_str() {
+                    "delay" => Instruction::LdDtVx(x),
+                    "buzzer" => Instruction::LdStVx(x),
+                    _ => Instruction::LdPitch(x),
+                })
+            }
+            "i" => self.index_assignment(),
+            "if" => self.if_statement(),
+            "else" => self.else_statement(),
+            "end" => self.end_statement(),
+            "loop" => {
+                self.blocks.push(Block::Loop {
+                    start: self.pc as u16,
+                    breaks: Vec::new(),
+                });
+                Ok(())
+            }
+            "while" => self.while_statement(),
+            "again" => self.again_statement(),
+            _ if token.starts_with(':') => self.error(format!("'{}' is not supported", token)),
+            // anything else names a subroutine, possibly one further down
+            _ => self.address_instruction(token, Instruction::Call),
+        }
+    }
+
+    fn define_label(&mut self, name: String, addr: usize) -> Result<(), OctoError> {
+        if self.register_named(&name).is_some() || self.constant_named(&name).is_some() {
+            return self.error(format!("'{}' can't be used as a label", name));
+        }
+        if self.labels.insert(name.clone(), addr as u16).is_some() {
+            return self.error(format!("label '{}' is defined twice", name));
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), OctoError> {
+        let start = self.pc - PROGRAM_START;
+        let end = start + bytes.len();
+        if self.pc + bytes.len() > 0x10000 {
+            return self.error("program doesn't fit in 64KB of memory");
+        }
+        if self.rom.len() < end {
+            self.rom.resize(end, 0);
+        }
+        self.rom[start..end].copy_from_slice(bytes);
+        self.pc += bytes.len();
+        Ok(())
+    }
+
+    fn instruction(&mut self, instruction: Instruction) -> Result<(), OctoError> {
+        if let Some(name) = self.next_label.take() {
+            self.define_label(name, self.pc + 1)?;
+        }
+        self.emit(&instruction.encode())
+    }
+
+    fn reference(&mut self, label: String, fixup: Fixup) {
+        self.fixups.push((self.pc, label, fixup, self.line));
+    }
+
+    // an NNN instruction, left at 0 and patched at the end if the label isn't known yet
+    fn address_instruction(
+        &mut self,
+        target: String,
+        instruction: fn(u16) -> Instruction,
+    ) -> Result<(), OctoError> {
+        let addr = match self.number_named(&target) {
+            Some(value) => self.to_address(value, 0xFFF)?,
+            None => {
+                self.reference(target, Fixup::Address);
+                0
+            }
+        };
+        self.instruction(instruction(addr))
+    }
+
+    fn long_address(&mut self, target: String) -> Result<(), OctoError> {
+        let addr = match self.number_named(&target) {
+            Some(value) => self.to_address(value, 0xFFFF)?,
+            None => {
+                self.reference(target, Fixup::Long);
+                0
+            }
+        };
+        self.emit(&addr.to_be_bytes())
+    }
+
+    fn unpack(&mut self, nibble: Option<u8>, target: String) -> Result<(), OctoError> {
+        let fixup = match nibble {
+            Some(high) => Fixup::Unpack(high),
+            None => Fixup::UnpackLong,
+        };
+
+        let start = self.pc;
+        self.instruction(Instruction::LdImm { x: 0, nn: 0 })?;
+        self.instruction(Instruction::LdImm { x: 1, nn: 0 })?;
+        match self.number_named(&target) {
+            Some(value) => {
+                let addr = self.to_address(value, 0xFFFF)?;
+                self.patch(start, addr, fixup)
+            }
+            None => {
+                self.fixups.push((start, target, fixup, self.line));
+                Ok(())
+            }
+        }
+    }
+
+    fn patch(&mut self, addr: usize, target: u16, fixup: Fixup) -> Result<(), OctoError> {
+        let offset = addr - PROGRAM_START;
+        match fixup {
+            Fixup::Address => {
+                if target > 0xFFF {
+                    return self.error(format!(
+                        "{:#x} is past 0xFFF, use 'i := long' or 'jump' from lower memory",
+                        target
+                    ));
+                }
+                self.rom[offset] = (self.rom[offset] & 0xF0) | (target >> 8) as u8;
+                self.rom[offset + 1] = target as u8;
+            }
+            Fixup::Long => {
+                self.rom[offset..offset + 2].copy_from_slice(&target.to_be_bytes());
+            }
+            Fixup::UnpackLong => {
+                self.rom[offset + 1] = (target >> 8) as u8;
+                self.rom[offset + 3] = target as u8;
+            }
+            Fixup::Unpack(high) => {
+                if target > 0xFFF {
+                    return self.error(format!("{:#x} doesn't fit in 12 bits", target));
+                }
+                self.rom[offset + 1] = (high << 4) | (target >> 8) as u8;
+                self.rom[offset + 3] = target as u8;
+            }
+        }
+        Ok(())
+    }
+
+    fn register_named(&self, name: &str) -> Option<u8> {
+        if let Some(&x) = self.aliases.get(name) {
+            return Some(x);
+        }
+        match name.as_bytes() {
+            [b'v' | b'V', digit] => (*digit as char).to_digit(16).map(|digit| digit as u8),
+            _ => None,
+        }
+    }
+
+    fn register(&mut self) -> Result<u8, OctoError> {
+        let token = self.next()?;
+        match self.register_named(&token) {
+            Some(x) => Ok(x),
+            None => self.error(format!("expected a register, found '{}'", token)),
+        }
+    }
+
+    // numbers, constants, and labels once they're defined
+    fn number_named(&self, text: &str) -> Option<f64> {
+        self.constant_named(text)
+            .or_else(|| self.labels.get(text).map(|&addr| addr as f64))
+    }
+
+    fn constant_named(&self, text: &str) -> Option<f64> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text),
+        };
+        let parsed = if let Some(hex) = digits.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok()
+        } else if let Some(binary) = digits.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2).ok()
+        } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+            digits.parse().ok()
+        } else {
+            None
+        };
+        if let Some(value) = parsed {
+            return Some(if negative { -value } else { value } as f64);
+        }
+
+        self.constants.get(text).copied()
+    }
+
+    fn number(&self, text: &str) -> Result<f64, OctoError> {
+        match self.number_named(text) {
+            Some(value) => Ok(value),
+            None => self.error(format!("'{}' is not a number or a defined name", text)),
+        }
+    }
+
+    // negative bytes wrap around, so `v0 += -1` works like Octo's
+    fn to_byte(&self, value: f64) -> Result<u8, OctoError> {
+        let value = value.floor();
+        if !(-128.0..=255.0).contains(&value) {
+            return self.error(format!("{} doesn't fit in a byte", value));
+        }
+        Ok(value as i64 as u8)
+    }
+
+    fn to_nibble(&self, value: f64) -> Result<u8, OctoError> {
+        let value = value.floor();
+        if !(0.0..=15.0).contains(&value) {
+            return self.error(format!("{} doesn't fit in a nibble", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn to_address(&self, value: f64, max: u16) -> Result<u16, OctoError> {
+        let value = value.floor();
+        if !(0.0..=max as f64).contains(&value) {
+            return self.error(format!("{} is out of range for an address", value));
+        }
+        Ok(value as u16)
+    }
+
+    fn byte(&mut self) -> Result<u8, OctoError> {
+        let token = self.next()?;
+        let value = self.number(&token)?;
+        self.to_byte(value)
+    }
+
+    fn nibble(&mut self) -> Result<u8, OctoError> {
+        let token = self.next()?;
+        let value = self.number(&token)?;
+        self.to_nibble(value)
+    }
+
+    fn operand(&mut self) -> Result<Operand, OctoError> {
+        let token = self.next()?;
+        if let Some(x) = self.register_named(&token) {
+            return Ok(Operand::Register(x));
+        }
+        let value = self.number(&token)?;
+        Ok(Operand::Byte(self.to_byte(value)?))
+    }
+
+    fn data(&mut self, token: &str, value: f64) -> Result<(), OctoError> {
+        match self.to_byte(value) {
+            Ok(byte) => self.emit(&[byte]),
+            Err(_) => self.error(format!("'{}' doesn't fit in a byte", token)),
+        }
+    }
+
+    fn assignment(&mut self, x: u8) -> Result<(), OctoError> {
+        let operator = self.next()?;
+        let instruction = match operator.as_str() {
+            ":=" => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    Instruction::Rnd {
+                        x,
+                        nn: self.byte()?,
+                    }
+                }
+                Some("key") => {
+                    self.next()?;
+                    Instruction::LdKey(x)
+                }
+                Some("delay") => {
+                    self.next()?;
+                    Instruction::LdVxDt(x)
+                }
+                _ => match self.operand()? {
+                    Operand::Register(y) => Instruction::LdReg { x, y },
+                    Operand::Byte(nn) => Instruction::LdImm { x, nn },
+                },
+            },
+            "+=" => match self.operand()? {
+                Operand::Register(y) => Instruction::AddReg { x, y },
+                Operand::Byte(nn) => Instruction::AddImm { x, nn },
+            },
+            "-=" => match self.operand()? {
+                Operand::Register(y) => Instruction::Sub { x, y },
+                Operand::Byte(nn) => Instruction::AddImm {
+                    x,
+                    nn: nn.wrapping_neg(),
+                },
+            },
+            "=-" | "|=" | "&=" | "^=" | ">>=" | "<<=" => {
+                let y = self.register()?;
+                match operator.as_str() {
+                    "=-" => Instruction::Subn { x, y },
+                    "|=" => Instruction::Or { x, y },
+                    "&=" => Instruction::And { x, y },
+                    "^=" => Instruction::Xor { x, y },
+                    ">>=" => Instruction::Shr { x, y },
+                    _ => Instruction::Shl { x, y },
+                }
+            }
+            _ => return self.error(format!("unknown operator '{}'", operator)),
+        };
+
+        self.instruction(instruction)
+    }
+
+    fn index_assignment(&mut self) -> Result<(), OctoError> {
+        let operator = self.next()?;
+        match operator.as_str() {
+            "+=" => {
+                let x = self.register()?;
+                self.instruction(Instruction::AddI(x))
+            }
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.instruction(Instruction::LdFont(x))
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.instruction(Instruction::LdBigFont(x))
+                }
+                Some("long") => {
+                    self.next()?;
+                    let target = self.next()?;
+                    if let Some(name) = self.next_label.take() {
+                        self.define_label(name, self.pc + 1)?;
+                    }
+                    self.emit(&[0xF0, 0x00])?;
+                    self.long_address(target)
+                }
+                _ => {
+                    let target = self.next()?;
+                    self.address_instruction(target, Instruction::LdI)
+                }
+            },
+            _ => self.error(format!("unknown operator 'i {}'", operator)),
+        }
+    }
+
+    fn save_load(&mut self, token: &str) -> Result<(), OctoError> {
+        let x = self.register()?;
+        let range_end = if self.peek() == Some("-") {
+            self.next()?;
+            Some(self.register()?)
+        } else {
+            None
+        };
+
+        self.instruction(match (token, range_end) {
+            ("save", None) => Instruction::StoreRegs(x),
+            ("save", Some(y)) => Instruction::SaveRange { x, y },
+            (_, None) => Instruction::LoadRegs(x),
+            (_, Some(y)) => Instruction::LoadRange { x, y },
+        })
+    }
+
+    fn comparison(&mut self) -> Result<(u8, Comparison, Option<Operand>), OctoError> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        let comparison = match operator.as_str() {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            "<=" => Comparison::LessEqual,
+            ">=" => Comparison::GreaterEqual,
+            "key" => return Ok((x, Comparison::Key, None)),
+            "-key" => return Ok((x, Comparison::NotKey, None)),
+            _ => return self.error(format!("unknown comparison '{}'", operator)),
+        };
+        Ok((x, comparison, Some(self.operand()?)))
+    }
+
+    // emits instructions that skip the next one unless the comparison holds
+    fn skip_unless(
+        &mut self,
+        x: u8,
+        comparison: Comparison,
+        operand: Option<Operand>,
+    ) -> Result<(), OctoError> {
+        let skip = match (comparison, operand) {
+            (Comparison::Key, _) => Instruction::Sknp(x),
+            (Comparison::NotKey, _) => Instruction::Skp(x),
+            (Comparison::Equal, Some(Operand::Register(y))) => Instruction::SneReg { x, y },
+            (Comparison::Equal, Some(Operand::Byte(nn))) => Instruction::SneImm { x, nn },
+            (Comparison::NotEqual, Some(Operand::Register(y))) => Instruction::SeReg { x, y },
+            (Comparison::NotEqual, Some(Operand::Byte(nn))) => Instruction::SeImm { x, nn },
+            // the rest go through VF: load the right side, subtract, then test the borrow flag
+            (_, Some(operand)) => {
+                self.instruction(match operand {
+                    Operand::Register(y) => Instruction::LdReg { x: VF, y },
+                    Operand::Byte(nn) => Instruction::LdImm { x: VF, nn },
+                })?;
+                // VF = 1 when x >= right side for < and >=, when right side >= x for > and <=
+                self.instruction(match comparison {
+                    Comparison::Less | Comparison::GreaterEqual => {
+                        Instruction::Subn { x: VF, y: x }
+                    }
+                    _ => Instruction::Sub { x: VF, y: x },
+                })?;
+                let holds_when_set =
+                    matches!(comparison, Comparison::GreaterEqual | Comparison::LessEqual);
+                Instruction::SeImm {
+                    x: VF,
+                    nn: if holds_when_set { 0 } else { 1 },
+                }
+            }
+            (_, None) => return self.error("comparison is missing its right side"),
+        };
+
+        self.instruction(skip)
+    }
+
+    fn if_statement(&mut self) -> Result<(), OctoError> {
+        let (x, comparison, operand) = self.comparison()?;
+        match self.next()?.as_str() {
+            // the next statement is the whole body
+            "then" => self.skip_unless(x, comparison, operand),
+            "begin" => {
+                self.skip_unless(x, comparison.inverse(), operand)?;
+                let jump = self.pc;
+                self.instruction(Instruction::Jp(0))?;
+                self.blocks.push(Block::If { jump });
+                Ok(())
+            }
+            other => self.error(format!("expected 'then' or 'begin', found '{}'", other)),
+        }
+    }
+
+    fn else_statement(&mut self) -> Result<(), OctoError> {
+        let Some(Block::If { jump }) = self.blocks.pop() else {
+            return self.error("'else' without 'if ... begin'");
+        };
+        let end_jump = self.pc;
+        self.instruction(Instruction::Jp(0))?;
+        self.patch(jump, self.pc as u16, Fixup::Address)?;
+        self.blocks.push(Block::Else { jump: end_jump });
+        Ok(())
+    }
+
+    fn end_statement(&mut self) -> Result<(), OctoError> {
+        match self.blocks.pop() {
+            Some(Block::If { jump } | Block::Else { jump }) => {
+                self.patch(jump, self.pc as u16, Fixup::Address)
+            }
+            _ => self.error("'end' without 'if ... begin'"),
+        }
+    }
+
+    fn while_statement(&mut self) -> Result<(), OctoError> {
+        let (x, comparison, operand) = self.comparison()?;
+        self.skip_unless(x, comparison.inverse(), operand)?;
+        let jump = self.pc;
+        self.instruction(Instruction::Jp(0))?;
+
+        match self.blocks.iter_mut().rev().find_map(|block| match block {
+            Block::Loop { breaks, .. } => Some(breaks),
+            _ => None,
+        }) {
+            Some(breaks) => {
+                breaks.push(jump);
+                Ok(())
+            }
+            None => self.error("'while' outside a loop"),
+        }
+    }
+
+    fn again_statement(&mut self) -> Result<(), OctoError> {
+        let Some(Block::Loop { start, breaks }) = self.blocks.pop() else {
+            return self.error("'again' without 'loop'");
+        };
+        self.instruction(Instruction::Jp(start))?;
+        for jump in breaks {
+            self.patch(jump, self.pc as u16, Fixup::Address)?;
+        }
+        Ok(())
+    }
+
+    fn define_macro(&mut self) -> Result<(), OctoError> {
+        let name = self.next()?;
+        let mut parameters = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token == "{" {
+                break;
+            }
+            parameters.push(token);
+        }
+
+        let body = self.braced_tokens()?;
+        self.macros.insert(name, Macro { parameters, body });
+        Ok(())
+    }
+
+    // everything up to the matching `}`, the `{` already taken
+    fn braced_tokens(&mut self) -> Result<Vec<Token>, OctoError> {
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let Some(token) = self.tokens.pop_front() else {
+                return self.error("'{' without a matching '}'");
+            };
+            self.line = token.line;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(body);
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), OctoError> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return self.error(format!("macro '{}' keeps expanding into itself", name));
+        }
+
+        let parameter_count = self.macros[name].parameters.len();
+        let mut arguments = HashMap::new();
+        for index in 0..parameter_count {
+            let argument = self.next()?;
+            arguments.insert(self.macros[name].parameters[index].clone(), argument);
+        }
+
+        let line = self.line;
+        let expansion: Vec<Token> = self.macros[name]
+            .body
+            .iter()
+            .map(|token| Token {
+                text: arguments
+                    .get(&token.text)
+                    .cloned()
+                    .unwrap_or_else(|| token.text.clone()),
+                // errors inside a macro point at where it was used
+                line,
+            })
+            .collect();
+        for token in expansion.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    fn braced_calc(&mut self) -> Result<f64, OctoError> {
+        self.expect("{")?;
+        let tokens: Vec<String> = self
+            .braced_tokens()?
+            .into_iter()
+            .map(|token| token.text)
+            .collect();
+        let mut position = 0;
+        let value = self.calc_expression(&tokens, &mut position)?;
+        if position != tokens.len() {
+            return self.error(format!("unexpected '{}' in expression", tokens[position]));
+        }
+        Ok(value)
+    }
+
+    // Octo evaluates :calc right to left with no precedence, `2 * 3 + 1` is 8
+    fn calc_expression(&self, tokens: &[String], position: &mut usize) -> Result<f64, OctoError> {
+        let left = self.calc_term(tokens, position)?;
+        let Some(operator) = tokens.get(*position) else {
+            return Ok(left);
+        };
+        if operator == ")" {
+            return Ok(left);
+        }
+
+        *position += 1;
+        let right = self.calc_expression(tokens, position)?;
+        let (a, b) = (left, right);
+        let (ia, ib) = (a as i64, b as i64);
+        Ok(match operator.as_str() {
+            "+" => a + b,
+            "-" => a - b,
+            "*" => a * b,
+            "/" => a / b,
+            "%" => a % b,
+            "&" => (ia & ib) as f64,
+            "|" => (ia | ib) as f64,
+            "^" => (ia ^ ib) as f64,
+            "<<" => (ia << ib) as f64,
+            ">>" => (ia >> ib) as f64,
+            "pow" => a.powf(b),
+            "min" => a.min(b),
+            "max" => a.max(b),
+            "<" => (a < b) as i64 as f64,
+            ">" => (a > b) as i64 as f64,
+            "<=" => (a <= b) as i64 as f64,
+            ">=" => (a >= b) as i64 as f64,
+            "==" => (a == b) as i64 as f64,
+            "!=" => (a != b) as i64 as f64,
+            _ => return self.error(format!("unknown operator '{}' in expression", operator)),
+        })
+    }
+
+    fn calc_term(&self, tokens: &[String], position: &mut usize) -> Result<f64, OctoError> {
+        let Some(token) = tokens.get(*position) else {
+            return self.error("expression ends too early");
+        };
+        *position += 1;
+
+        let unary = |operator: &str, value: f64| -> Option<f64> {
+            Some(match operator {
+                "-" => -value,
+                "~" => !(value as i64) as f64,
+                "!" => (value == 0.0) as i64 as f64,
+                "abs" => value.abs(),
+                "sqrt" => value.sqrt(),
+                "sin" => value.sin(),
+                "cos" => value.cos(),
+                "tan" => value.tan(),
+                "exp" => value.exp(),
+                "log" => value.ln(),
+                "sign" => value.signum(),
+                "floor" => value.floor(),
+                "ceil" => value.ceil(),
+                _ => return None,
+            })
+        };
+
+        match token.as_str() {
+            "(" => {
+                let value = self.calc_expression(tokens, position)?;
+                if tokens.get(*position).map(String::as_str) != Some(")") {
+                    return self.error("'(' without a matching ')'");
+                }
+                *position += 1;
+                Ok(value)
+            }
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            "HERE" => Ok(self.pc as f64),
+            _ => match self.number_named(token) {
+                Some(value) => Ok(value),
+                None if unary(token, 0.0).is_some() => {
+                    let value = self.calc_term(tokens, position)?;
+                    Ok(unary(token, value).unwrap_or(value))
+                }
+                None => self.error(format!("'{}' is not defined", token)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn statements_test() {
+        let rom = compile(
+            "
+            : main
+                v0 := 5          # 6005
+                v1 += v0         # 8104
+                v2 -= 1          # 72FF
+                i := sprite      # A20C
+                sprite v0 v1 4   # D014
+                jump main        # 1200
+            : sprite
+                0xF0 0x90
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                0x60, 0x05, 0x81, 0x04, 0x72, 0xFF, 0xA2, 0x0C, 0xD0, 0x14, 0x12, 0x00, 0xF0, 0x90
+            ],
+            rom
+        );
+    }
+
+    #[test]
+    fn control_flow_test() {
+        let rom = compile(
+            "
+            : main
+                loop
+                    v0 += 1
+                    if v0 == 3 then v1 := 1
+                    if v1 key begin
+                        v2 := 2
+                    else
+                        v2 := 3
+                    end
+                    while v0 != 10
+                again
+                exit
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                0x70, 0x01, // v0 += 1
+                0x40, 0x03, 0x61, 0x01, // skip unless v0 == 3, v1 := 1
+                0xE1, 0x9E, 0x12, 0x0E, // skip the jump to else if pressed
+                0x62, 0x02, 0x12, 0x10, // v2 := 2, jump over else
+                0x62, 0x03, // v2 := 3
+                0x40, 0x0A, 0x12, 0x16, // while v0 != 10, or break out
+                0x12, 0x00, // again
+                0x00, 0xFD,
+            ],
+            rom
+        );
+    }
+
+    #[test]
+    fn forward_calls_macros_and_calc_test() {
+        let rom = compile(
+            "
+            :macro twice op reg { op reg op reg }
+            :const BASE 0x10
+            :calc SPEED { BASE * 2 + 1 }
+            draw
+            : main
+                twice bcd v3
+                v4 := SPEED
+                if v4 < 8 then v5 := 1
+            : draw
+                return
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                0x12, 0x04, // jump main, main isn't first
+                0x22, 0x12, // draw, called before it's defined
+                0xF3, 0x33, 0xF3, 0x33, // twice bcd v3
+                0x64, 0x30, // 0x10 * (2 + 1) = 48
+                0x6F, 0x08, 0x8F, 0x47, 0x3F, 0x01, // vf := 8, vf =- v4, skip if vf == 1
+                0x65, 0x01, 0x00, 0xEE,
+            ],
+            rom
+        );
+    }
+
+    #[test]
+    fn disassembly_round_trip_test() {
+        use crate::disassembler::{Disassembly, Syntax};
+
+        let rom = [
+            0x22, 0x08, 0xF0, 0x00, 0x02, 0x10, 0x12, 0x06, 0xA2, 0x10, 0xE1, 0x9E, 0x00, 0xEE,
+            0x12, 0x34, 0xF0, 0x90, 0x90, 0xF0,
+        ];
+        let listing = Disassembly::new(&rom).listing(Syntax::Octo);
+
+        assert_eq!(rom.to_vec(), compile(&listing).unwrap());
+    }
+
+    #[test]
+    fn errors_test() {
+        assert_eq!(
+            OctoError {
+                line: 3,
+                message: String::from("expected a register, found 'v16'")
+            },
+            compile(": main\n v0 := 1\n bcd v16\n").unwrap_err()
+        );
+        assert!(compile("v0 := 1").is_err());
+        assert!(compile(": main loop v0 += 1").is_err());
+        assert!(compile(": main jump nowhere").is_err());
+        assert!(compile(": main v0 := 256").is_err());
+    }
+}
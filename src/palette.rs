@@ -0,0 +1,181 @@
+use crate::config::{Config, Setting};
+
+/// The four colours a pixel can be, indexed by `Chip8::pixel_color`: off, XO-CHIP
+/// plane 0 only, plane 1 only, and both planes. Plain CHIP-8 only uses the first two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [[u8; 3]; 4],
+}
+
+impl Palette {
+    pub const CLASSIC: Palette = Palette {
+        colors: [
+            [0x00, 0x00, 0x00],
+            [0xFF, 0xFF, 0xFF],
+            [0xAA, 0xAA, 0xAA],
+            [0x55, 0x55, 0x55],
+        ],
+    };
+
+    /// Amber monochrome monitor.
+    pub const AMBER: Palette = Palette {
+        colors: [
+            [0x1A, 0x10, 0x00],
+            [0xFF, 0xB0, 0x00],
+            [0xB3, 0x6B, 0x00],
+            [0x66, 0x3D, 0x00],
+        ],
+    };
+
+    /// P1 green phosphor, like the terminals of the day.
+    pub const GREEN: Palette = Palette {
+        colors: [
+            [0x00, 0x1A, 0x00],
+            [0x33, 0xFF, 0x33],
+            [0x1A, 0x99, 0x1A],
+            [0x0D, 0x4D, 0x0D],
+        ],
+    };
+
+    /// Octo's default colours, what most XO-CHIP games were made with.
+    pub const OCTO: Palette = Palette {
+        colors: [
+            [0x99, 0x66, 0x00],
+            [0xFF, 0xCC, 0x00],
+            [0xFF, 0x66, 0x00],
+            [0x66, 0x22, 0x00],
+        ],
+    };
+
+    /// Dark pixels on a greenish reflective LCD.
+    pub const LCD: Palette = Palette {
+        colors: [
+            [0x9B, 0xBC, 0x0F],
+            [0x0F, 0x38, 0x0F],
+            [0x30, 0x62, 0x30],
+            [0x8B, 0xAC, 0x0F],
+        ],
+    };
+
+    /// Every named palette, in the order the palette hotkey cycles through them.
+    pub const PRESETS: [(&'static str, Palette); 5] = [
+        ("classic", Palette::CLASSIC),
+        ("amber", Palette::AMBER),
+        ("green", Palette::GREEN),
+        ("octo", Palette::OCTO),
+        ("lcd", Palette::LCD),
+    ];
+
+    pub fn named(name: &str) -> Option<Palette> {
+        Palette::PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name.trim()))
+            .map(|&(_, palette)| palette)
+    }
+
+    /// Reads a preset name, or comma separated `rrggbb` colours. With only a background
+    /// and a foreground the two XO-CHIP plane colours are blended in between.
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        if let Some(palette) = Palette::named(text) {
+            return Ok(palette);
+        }
+        if !text.contains(',') {
+            return Err(format!(
+                "unknown palette '{}', use classic, amber, green, octo, lcd or rrggbb colours",
+                text
+            ));
+        }
+
+        let colors = text
+            .split(',')
+            .map(|color| {
+                let hex = color.trim().trim_start_matches('#');
+                match u32::from_str_radix(hex, 16) {
+                    Ok(rgb) if hex.len() == 6 => {
+                        Ok([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8])
+                    }
+                    _ => Err(format!("'{}' is not an rrggbb colour", color)),
+                }
+            })
+            .collect::<Result<Vec<[u8; 3]>, String>>()?;
+
+        match colors[..] {
+            [off, on] => Ok(Palette {
+                colors: [
+                    off,
+                    on,
+                    Palette::blend(off, on, 2),
+                    Palette::blend(off, on, 1),
+                ],
+            }),
+            [off, plane0, plane1, both] => Ok(Palette {
+                colors: [off, plane0, plane1, both],
+            }),
+            _ => Err(String::from("a palette needs 2 or 4 colours")),
+        }
+    }
+
+    /// Applies `palette = ...` settings, the last one wins.
+    pub fn configure(&mut self, config: &Config, settings: &[&Setting]) -> Result<(), String> {
+        for setting in settings.iter().filter(|setting| setting.name == "palette") {
+            *self = Palette::parse(&setting.value).map_err(|e| config.error(setting.line, &e))?;
+        }
+
+        Ok(())
+    }
+
+    // thirds of the way from `from` to `to`
+    fn blend(from: [u8; 3], to: [u8; 3], thirds: u16) -> [u8; 3] {
+        let mut blended = [0; 3];
+        for (channel, value) in blended.iter_mut().enumerate() {
+            let (from, to) = (from[channel] as u16, to[channel] as u16);
+            *value = ((from * (3 - thirds) + to * thirds) / 3) as u8;
+        }
+        blended
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::CLASSIC
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_palette_test() {
+        assert_eq!(Ok(Palette::CLASSIC), Palette::parse("000000,ffffff"));
+        assert_eq!(
+            Ok([0x12, 0x34, 0x56]),
+            Palette::parse("#000000, 111111, 222222, 123456").map(|palette| palette.colors[3])
+        );
+        assert_eq!(Ok(Palette::AMBER), Palette::parse("Amber"));
+        assert!(Palette::parse("000000").is_err());
+        assert!(Palette::parse("sepia").is_err());
+        assert!(Palette::parse("000000,fffff").is_err());
+    }
+
+    #[test]
+    fn configure_palette_test() {
+        let config = Config::parse(
+            "chip8.conf",
+            "palette = green\n[Game.ch8]\npalette = 000000,ff0000",
+        )
+        .unwrap();
+
+        let mut palette = Palette::default();
+        palette
+            .configure(&config, &config.settings("Other.ch8"))
+            .unwrap();
+        assert_eq!(Palette::GREEN, palette);
+
+        palette
+            .configure(&config, &config.settings("Game.ch8"))
+            .unwrap();
+        assert_eq!([0xFF, 0x00, 0x00], palette.colors[1]);
+    }
+}
@@ -0,0 +1,89 @@
+/// Afterglow for the renderer, so pixels fade out over a few frames the way a CRT's
+/// phosphor did instead of vanishing. Sprites that are erased and redrawn every frame
+/// (the usual CHIP-8 way of moving them) then stop flickering.
+///
+/// A pixel that's on is shown at full strength at once; one that's off fades from
+/// whatever was shown towards its colour, keeping `decay` of the difference a frame.
+pub struct Phosphor {
+    decay: f32,
+    // what each pixel showed last frame, RGB
+    glow: Vec<[f32; 3]>,
+    fading: bool,
+}
+
+// close enough to the target colour to count as faded
+const SETTLED: f32 = 0.5;
+
+impl Phosphor {
+    /// `decay` is how much of a pixel's light is left after a frame, from 0 (none,
+    /// no afterglow) to just under 1.
+    pub fn new(decay: f32) -> Phosphor {
+        Phosphor {
+            decay: decay.clamp(0.0, 0.99),
+            glow: Vec::new(),
+            fading: false,
+        }
+    }
+
+    /// Blends in the next frame. `pixels` holds the RGB24 colours the framebuffer asks
+    /// for and is overwritten with the ones to show, `lit` says which pixels are on.
+    pub fn apply(&mut self, pixels: &mut [u8], lit: &[bool]) {
+        if self.glow.len() != lit.len() {
+            // the resolution changed, the old glow doesn't line up any more
+            self.glow = pixels
+                .chunks_exact(3)
+                .map(|rgb| [rgb[0] as f32, rgb[1] as f32, rgb[2] as f32])
+                .collect();
+        }
+
+        self.fading = false;
+        for ((rgb, glow), &lit) in pixels.chunks_exact_mut(3).zip(&mut self.glow).zip(lit) {
+            for (value, glow) in rgb.iter_mut().zip(glow.iter_mut()) {
+                let target = *value as f32;
+                if lit {
+                    *glow = target;
+                } else {
+                    *glow = target + (*glow - target) * self.decay;
+                    self.fading |= (*glow - target).abs() > SETTLED;
+                }
+                *value = glow.round() as u8;
+            }
+        }
+    }
+
+    /// True while some pixel is still fading, so the screen needs drawing again even
+    /// if the framebuffer hasn't changed.
+    pub fn is_fading(&self) -> bool {
+        self.fading
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn phosphor_fade_test() {
+        let mut phosphor = Phosphor::new(0.5);
+        let mut frame = |rgb: [u8; 3], lit| {
+            let mut pixels = rgb;
+            phosphor.apply(&mut pixels, &[lit]);
+            pixels
+        };
+        let (off, on) = ([0, 0, 0], [200, 100, 0]);
+
+        assert_eq!(on, frame(on, true));
+        assert_eq!([100, 50, 0], frame(off, false));
+        assert_eq!([50, 25, 0], frame(off, false));
+        // coming back on is instant
+        assert_eq!(on, frame(on, true));
+
+        frame(off, false);
+        assert!(phosphor.is_fading());
+        for _ in 0..10 {
+            phosphor.apply(&mut [0, 0, 0], &[false]);
+        }
+        assert!(!phosphor.is_fading());
+    }
+}
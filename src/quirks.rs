@@ -0,0 +1,71 @@
+/// What FX55/FX65 do to I after copying the registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexIncrement {
+    /// I is left alone (SUPER-CHIP 1.1)
+    Unchanged,
+    /// I += X, the CHIP-48 off-by-one
+    ByX,
+    /// I += X + 1, the original COSMAC VIP behaviour
+    ByXPlusOne,
+}
+
+/// The ambiguous corners of the instruction set. Different interpreters disagree
+/// on these, and ROMs tend to only run correctly on the one they were written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift Vy into Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    pub load_store_index: IndexIncrement,
+    /// BNNN is read as BXNN and jumps to XNN + Vx instead of NNN + V0
+    pub jump_uses_vx: bool,
+    /// 8XY1/8XY2/8XY3 set VF to 0
+    pub logic_resets_vf: bool,
+    /// sprites are cut off at the screen edge instead of wrapping around
+    pub sprites_clip: bool,
+    /// DXYN waits for the next 60 Hz frame before anything else runs
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_index: IndexIncrement::ByXPlusOne,
+        jump_uses_vx: false,
+        logic_resets_vf: true,
+        sprites_clip: true,
+        display_wait: true,
+    };
+
+    pub const CHIP_48: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_index: IndexIncrement::ByX,
+        jump_uses_vx: true,
+        logic_resets_vf: false,
+        sprites_clip: true,
+        display_wait: false,
+    };
+
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_index: IndexIncrement::Unchanged,
+        jump_uses_vx: true,
+        logic_resets_vf: false,
+        sprites_clip: true,
+        display_wait: false,
+    };
+
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_index: IndexIncrement::ByXPlusOne,
+        jump_uses_vx: false,
+        logic_resets_vf: false,
+        sprites_clip: false,
+        display_wait: false,
+    };
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::COSMAC_VIP
+    }
+}
@@ -0,0 +1,43 @@
+/// Where CXNN gets its random numbers. `Chip8` owns one so runs can be made
+/// reproducible: replays and tests seed it, save states capture its state.
+pub trait RandomSource {
+    fn next_byte(&mut self) -> u8;
+    /// The whole generator state, written into save states.
+    fn state(&self) -> u64;
+    fn set_state(&mut self, state: u64);
+}
+
+/// SplitMix64, small and fast with a single word of state. Every seed works, 0 included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> SplitMix64 {
+        SplitMix64 { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl RandomSource for SplitMix64 {
+    fn next_byte(&mut self) -> u8 {
+        // the high bits are the best mixed
+        (self.next_u64() >> 56) as u8
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.state = state;
+    }
+}
@@ -1,43 +1,152 @@
 extern crate sdl2;
-use crate::chip8::Chip8;
-use sdl2::pixels::Color;
+use crate::chip8::{Chip8, DEFAULT_CHIP8_PIXEL_HEIGHT, DEFAULT_CHIP8_PIXEL_WIDTH};
+use crate::crt::{self, CrtFilter};
+use crate::palette::Palette;
+use crate::phosphor::Phosphor;
+use crate::viewport::Viewport;
+use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
-use sdl2::render::WindowCanvas;
+use sdl2::render::{Texture, WindowCanvas};
 use sdl2::video::Window;
 
+// RGB24
+const BYTES_PER_PIXEL: usize = 3;
+
 pub struct Renderer {
     canvas: WindowCanvas,
+    integer_scale: bool,
+    // the framebuffer goes up as one texture a frame and SDL scales it to the window.
+    // With sdl2's unsafe_textures it belongs to the canvas, which frees it on the way out
+    texture: Texture,
+    texture_size: (u32, u32),
+    pixels: Vec<u8>,
+    // indexed by the XO-CHIP plane bits: off, plane 0 only, plane 1 only, both
+    plane_colors: [[u8; 3]; 4],
+    // None without --persistence
+    phosphor: Option<Phosphor>,
+    // which of `pixels` are on, for the phosphor
+    lit: Vec<bool>,
+    crt: CrtFilter,
+    // `pixels` after the CRT filter
+    filtered: Vec<u8>,
 }
 
 impl Renderer {
-    pub fn new(window: Window) -> Result<Renderer, String> {
+    /// `integer_scale` only scales by whole multiples, see `Viewport::fit`. A
+    /// `persistence` above 0 lets pixels fade out over a few frames, see `Phosphor`.
+    pub fn new(
+        window: Window,
+        palette: Palette,
+        integer_scale: bool,
+        persistence: f32,
+        crt: CrtFilter,
+    ) -> Result<Renderer, String> {
         let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
-        Ok(Renderer { canvas })
+
+        let texture_size = (DEFAULT_CHIP8_PIXEL_WIDTH, DEFAULT_CHIP8_PIXEL_HEIGHT);
+        let texture = Renderer::create_texture(&canvas, texture_size)?;
+
+        Ok(Renderer {
+            canvas,
+            integer_scale,
+            texture,
+            texture_size,
+            pixels: Vec::new(),
+            plane_colors: palette.colors,
+            phosphor: (persistence > 0.0).then(|| Phosphor::new(persistence)),
+            lit: Vec::new(),
+            crt,
+            filtered: Vec::new(),
+        })
     }
 
-    pub fn get_size(&self) -> Result<(u32, u32), String> {
-        self.canvas.output_size()
+    // takes effect on the next draw
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.plane_colors = palette.colors;
     }
 
-    pub fn draw(&mut self, chip8: &mut Chip8) {
-        self.canvas.set_draw_color(Color::BLACK);
-        self.canvas.clear();
+    // switches the CRT filter on or off for the next draw, true if it's now on
+    pub fn toggle_crt(&mut self) -> bool {
+        self.crt.enabled = !self.crt.enabled;
+        self.crt.enabled
+    }
+
+    // pixels are still fading out, so drawing again would change the picture
+    pub fn is_fading(&self) -> bool {
+        self.phosphor.as_ref().is_some_and(Phosphor::is_fading)
+    }
+
+    pub fn window_mut(&mut self) -> &mut Window {
+        self.canvas.window_mut()
+    }
 
-        chip8.vram.iter_mut().for_each(|row| {
-            row.iter_mut().for_each(|pixel| {
-                let color = if pixel.on { Color::WHITE } else { Color::BLACK };
-                self.canvas.set_draw_color(color);
+    pub fn draw(&mut self, chip8: &mut Chip8) {
+        let (width, height) = (chip8.display_width(), chip8.display_height());
+        let texture_size = if self.crt.enabled {
+            (crt::OUTPUT_WIDTH, crt::OUTPUT_HEIGHT)
+        } else {
+            (width, height)
+        };
+        if self.texture_size != texture_size {
+            // 00FE/00FF changed resolution or the CRT filter was toggled
+            match Renderer::create_texture(&self.canvas, texture_size) {
+                Ok(texture) => {
+                    let old = std::mem::replace(&mut self.texture, texture);
+                    // safe while the canvas that made it is alive, which it is
+                    unsafe { old.destroy() };
+                    self.texture_size = texture_size;
+                }
+                Err(e) => {
+                    eprintln!("Could not resize the display texture: {}", e);
+                    return;
+                }
+            }
+        }
 
-                self.canvas.fill_rect(Rect::new(
-                    (pixel.x * chip8.vram_scale as u32) as i32,
-                    (pixel.y * chip8.vram_scale as u32) as i32,
-                    chip8.vram_scale as u32,
-                    chip8.vram_scale as u32,
-                ));
-            });
-        });
+        self.pixels.clear();
+        self.lit.clear();
+        for y in 0..height {
+            let (plane0, plane1) = (chip8.vram[0].row(y), chip8.vram[1].row(y));
+            for x in 0..width {
+                let bit = 127 - x;
+                let color = (plane0 >> bit & 1) | (plane1 >> bit & 1) << 1;
+                self.pixels
+                    .extend_from_slice(&self.plane_colors[color as usize]);
+                self.lit.push(color != 0);
+            }
+        }
+        if let Some(phosphor) = &mut self.phosphor {
+            phosphor.apply(&mut self.pixels, &self.lit);
+        }
 
+        let pixels = if self.crt.enabled {
+            self.crt
+                .apply(&self.pixels, width, height, &mut self.filtered);
+            &self.filtered
+        } else {
+            &self.pixels
+        };
+        let pitch = self.texture_size.0 as usize * BYTES_PER_PIXEL;
+        if let Err(e) = self.texture.update(None, pixels, pitch) {
+            eprintln!("Could not update the display texture: {}", e);
+        }
+        // the window can be any size, the display is scaled up into the middle of it.
+        // Hi-res mode and the CRT filter fill the same area with a bigger texture
+        let (window_width, window_height) = self.canvas.output_size().unwrap_or_default();
+        let viewport = Viewport::fit(window_width, window_height, self.integer_scale);
+        let destination = Rect::new(viewport.x, viewport.y, viewport.width, viewport.height);
+        self.canvas.clear();
+        let _ = self.canvas.copy(&self.texture, None, destination);
         self.canvas.present();
         chip8.vram_changed = false;
     }
+
+    fn create_texture(
+        canvas: &WindowCanvas,
+        (width, height): (u32, u32),
+    ) -> Result<Texture, String> {
+        canvas
+            .create_texture_streaming(PixelFormatEnum::RGB24, width, height)
+            .map_err(|e| e.to_string())
+    }
 }
@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+
+// one step back in time: the byte runs that differ from the newer state
+enum Delta {
+    Runs {
+        len: usize,
+        runs: Vec<(usize, Vec<u8>)>,
+    },
+    // the state changed size (stack depth, hi-res vram), so diffing isn't worth it
+    Full(Vec<u8>),
+}
+
+/// Ring buffer of per-frame save states for stepping backwards in time.
+///
+/// Only the newest state is kept whole. Older ones are stored as deltas against
+/// the state after them, which are tiny since most of memory doesn't change
+/// between frames.
+pub struct RewindBuffer {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    // oldest delta at the front
+    history: VecDeque<Delta>,
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            capacity,
+            latest: None,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.history.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.history.clear();
+    }
+
+    /// Records the newest state, from `Chip8::save_state`.
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.latest.take() {
+            self.history.push_back(RewindBuffer::diff(&state, previous));
+        }
+        self.latest = Some(state);
+
+        while self.len() > self.capacity && self.history.pop_front().is_some() {}
+    }
+
+    /// Steps back a frame: the newest state is where the machine is now, so it's
+    /// dropped and the one before it returned. Repeated calls walk backwards.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let delta = self.history.pop_back()?;
+        let latest = self.latest.as_ref()?;
+        let previous = RewindBuffer::apply(latest, delta);
+        self.latest = Some(previous.clone());
+
+        Some(previous)
+    }
+
+    fn diff(newer: &[u8], older: Vec<u8>) -> Delta {
+        if newer.len() != older.len() {
+            return Delta::Full(older);
+        }
+
+        let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+        for (offset, (new, old)) in newer.iter().zip(&older).enumerate() {
+            if new == old {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((start, bytes)) if *start + bytes.len() == offset => bytes.push(*old),
+                _ => runs.push((offset, vec![*old])),
+            }
+        }
+
+        Delta::Runs {
+            len: older.len(),
+            runs,
+        }
+    }
+
+    fn apply(newer: &[u8], delta: Delta) -> Vec<u8> {
+        match delta {
+            Delta::Full(older) => older,
+            Delta::Runs { len, runs } => {
+                let mut older = newer[..len].to_vec();
+                for (start, bytes) in runs {
+                    older[start..start + bytes.len()].copy_from_slice(&bytes);
+                }
+                older
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn rewind_walks_backwards_test() {
+        let mut buffer = RewindBuffer::new(10);
+        buffer.push(vec![1, 2, 3, 4]);
+        buffer.push(vec![1, 9, 9, 4]);
+        buffer.push(vec![1, 9, 9, 4, 5]);
+        buffer.push(vec![0, 9, 9, 4, 5]);
+
+        assert_eq!(Some(vec![1, 9, 9, 4, 5]), buffer.pop());
+        assert_eq!(Some(vec![1, 9, 9, 4]), buffer.pop());
+        assert_eq!(Some(vec![1, 2, 3, 4]), buffer.pop());
+        // the oldest state is where the machine now is, there's nothing before it
+        assert_eq!(None, buffer.pop());
+        assert_eq!(1, buffer.len());
+    }
+
+    #[test]
+    fn rewind_drops_oldest_frames_test() {
+        let mut buffer = RewindBuffer::new(3);
+        for frame in 0..5u8 {
+            buffer.push(vec![frame; 8]);
+        }
+
+        assert_eq!(3, buffer.len());
+        assert_eq!(Some(vec![3; 8]), buffer.pop());
+        assert_eq!(Some(vec![2; 8]), buffer.pop());
+        assert_eq!(None, buffer.pop());
+    }
+
+    #[test]
+    fn rewind_leaves_current_frame_test() {
+        let mut buffer = RewindBuffer::new(10);
+        buffer.push(vec![1; 4]);
+        buffer.push(vec![2; 4]);
+
+        // [2; 4] is what the machine is showing, rewinding has to go past it
+        assert_eq!(Some(vec![1; 4]), buffer.pop());
+
+        // carrying on and rewinding again doesn't repeat a frame either
+        buffer.push(vec![3; 4]);
+        assert_eq!(Some(vec![1; 4]), buffer.pop());
+    }
+}
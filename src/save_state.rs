@@ -0,0 +1,133 @@
+use crate::error::Chip8Error;
+
+/// Tags the start of every save state.
+pub const SAVE_STATE_MAGIC: &[u8; 4] = b"C8ST";
+/// Bumped whenever the layout written by `Chip8::save_state` changes.
+pub const SAVE_STATE_VERSION: u8 = 2;
+
+// little endian byte writer for `Chip8::save_state`
+pub(crate) struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new() -> StateWriter {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(SAVE_STATE_MAGIC);
+        bytes.push(SAVE_STATE_VERSION);
+        StateWriter { bytes }
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub(crate) fn bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, value: &[u8]) {
+        self.bytes.extend_from_slice(value);
+    }
+
+    // eight pixels to a byte, most significant bit first
+    pub(crate) fn bits(&mut self, value: impl Iterator<Item = bool>) {
+        let mut byte = 0u8;
+        let mut count = 0;
+        for bit in value {
+            byte = (byte << 1) | bit as u8;
+            count += 1;
+            if count == 8 {
+                self.bytes.push(byte);
+                byte = 0;
+                count = 0;
+            }
+        }
+        if count != 0 {
+            self.bytes.push(byte << (8 - count));
+        }
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+// the matching reader, every read fails cleanly on a truncated state
+pub(crate) struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Result<StateReader<'a>, Chip8Error> {
+        let mut reader = StateReader { bytes };
+        if reader.bytes(SAVE_STATE_MAGIC.len())? != SAVE_STATE_MAGIC {
+            return Err(Chip8Error::InvalidSaveState("not a save state"));
+        }
+        if reader.u8()? != SAVE_STATE_VERSION {
+            return Err(Chip8Error::InvalidSaveState(
+                "unsupported save state version",
+            ));
+        }
+
+        Ok(reader)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, Chip8Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, Chip8Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Chip8Error::InvalidSaveState("corrupt flag")),
+        }
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, Chip8Error> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, Chip8Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], Chip8Error> {
+        if len > self.bytes.len() {
+            return Err(Chip8Error::InvalidSaveState("save state is truncated"));
+        }
+
+        let (value, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(value)
+    }
+
+    pub(crate) fn bits(&mut self, len: usize) -> Result<Vec<bool>, Chip8Error> {
+        let bytes = self.bytes(len.div_ceil(8))?;
+        Ok((0..len)
+            .map(|bit| (bytes[bit / 8] >> (7 - bit % 8)) & 1 == 1)
+            .collect())
+    }
+
+    pub(crate) fn finish(self) -> Result<(), Chip8Error> {
+        if !self.bytes.is_empty() {
+            return Err(Chip8Error::InvalidSaveState(
+                "trailing data after save state",
+            ));
+        }
+
+        Ok(())
+    }
+}
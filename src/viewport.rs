@@ -0,0 +1,70 @@
+use crate::chip8::{DEFAULT_CHIP8_PIXEL_HEIGHT, DEFAULT_CHIP8_PIXEL_WIDTH};
+
+/// Where in the window the display goes: as big as fits at its 2:1 shape, centred,
+/// with black bars filling the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Viewport {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Viewport {
+    /// `integer_scale` only scales by whole multiples of 64x32, so every CHIP-8 pixel
+    /// is the same size. A window too small for even 1x gets the plain fit instead.
+    pub fn fit(window_width: u32, window_height: u32, integer_scale: bool) -> Viewport {
+        let whole_scale = (window_width / DEFAULT_CHIP8_PIXEL_WIDTH)
+            .min(window_height / DEFAULT_CHIP8_PIXEL_HEIGHT);
+        let (width, height) = if integer_scale && whole_scale > 0 {
+            (
+                DEFAULT_CHIP8_PIXEL_WIDTH * whole_scale,
+                DEFAULT_CHIP8_PIXEL_HEIGHT * whole_scale,
+            )
+        } else if window_width / 2 >= window_height {
+            // wider than 2:1, bars at the sides
+            (window_height * 2, window_height)
+        } else {
+            (window_width, window_width / 2)
+        };
+
+        Viewport {
+            x: ((window_width - width) / 2) as i32,
+            y: ((window_height - height) / 2) as i32,
+            width,
+            height,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn viewport(x: i32, y: i32, width: u32, height: u32) -> Viewport {
+        Viewport {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn fit_test() {
+        assert_eq!(viewport(0, 0, 640, 320), Viewport::fit(640, 320, false));
+        // wider than 2:1 and taller than 2:1
+        assert_eq!(viewport(320, 0, 1280, 640), Viewport::fit(1920, 640, false));
+        assert_eq!(viewport(0, 150, 600, 300), Viewport::fit(600, 600, false));
+    }
+
+    #[test]
+    fn integer_scale_test() {
+        // 700x400 fits 10x but not 11x
+        assert_eq!(viewport(30, 40, 640, 320), Viewport::fit(700, 400, true));
+        assert_eq!(viewport(0, 0, 640, 320), Viewport::fit(640, 320, true));
+        // smaller than 1x falls back to filling the window
+        assert_eq!(viewport(0, 5, 50, 25), Viewport::fit(50, 35, true));
+    }
+}